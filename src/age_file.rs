@@ -0,0 +1,182 @@
+//! Reading and writing files in the age v1 format
+//! (<https://age-encryption.org>), so they can be exchanged with the `age`
+//! command line tool.
+//!
+//! A passphrase becomes an scrypt recipient. Files can also be encrypted to
+//! the X25519 public keys in a recipients file, or to those of the identity
+//! file, which is what decrypts them. Directories aren't supported, since
+//! age has no notion of them.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, Error, ErrorKind, Read, Result, Write},
+    path::Path,
+};
+
+use age::{
+    scrypt, secrecy::SecretString, x25519, DecryptError, Decryptor, EncryptError, Encryptor,
+    Identity, IdentityFile, Recipient,
+};
+use zeroize::Zeroizing;
+
+use crate::{
+    crypto_handler::{check_output, write_atomic, CryptoError},
+    jobs::{Progress, Tracked},
+    key_source::KeySource,
+    recipient::key_lines,
+};
+
+pub const AGE_MAGIC: &[u8] = b"age-encryption.org/v1\n";
+
+pub struct AgeEncryptor {
+    source: KeySource,
+}
+
+impl AgeEncryptor {
+    pub fn new(source: KeySource) -> Self {
+        Self { source }
+    }
+
+    pub fn encrypt_file(&self, path: &Path, output: &Path, progress: &impl Progress) -> Result<()> {
+        if !path.is_file() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Only files can be encrypted to age",
+            ));
+        }
+        let encryptor = match &self.source {
+            KeySource::Passphrase(passphrase) => {
+                Encryptor::with_user_passphrase(SecretString::from(passphrase.to_string()))
+            }
+            KeySource::Identity(identity) => {
+                let recipients = identity_file(identity)?
+                    .to_recipients()
+                    .map_err(encrypt_error)?;
+                Encryptor::with_recipients(
+                    recipients
+                        .iter()
+                        .map(|recipient| recipient.as_ref() as &dyn Recipient),
+                )
+                .map_err(encrypt_error)?
+            }
+            KeySource::Recipients(path) => {
+                let recipients = key_lines(path)?
+                    .iter()
+                    .map(|line| {
+                        line.parse::<x25519::Recipient>().map_err(|_| {
+                            Error::new(ErrorKind::InvalidData, "Not a valid recipient key")
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Encryptor::with_recipients(
+                    recipients
+                        .iter()
+                        .map(|recipient| recipient as &dyn Recipient),
+                )
+                .map_err(encrypt_error)?
+            }
+            _ => return Err(CryptoError::WrongKeyType("a passphrase or an age identity").into()),
+        };
+
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        progress.total(size);
+        let mut reader = Tracked::new(file.take(size), progress);
+        write_atomic(output, |output| {
+            let mut writer = encryptor.wrap_output(output)?;
+            io::copy(&mut reader, &mut writer)?;
+            writer.finish()?.flush()
+        })
+    }
+
+    /// Decrypts the age file at `path` into `file_path`, replacing an
+    /// existing file only when `overwrite` is set.
+    pub fn decrypt_file(
+        &self,
+        path: &Path,
+        file_path: &Path,
+        overwrite: bool,
+        progress: &impl Progress,
+    ) -> Result<()> {
+        check_output(file_path, overwrite)?;
+        let mut reader = self.open(path, progress)?;
+        write_atomic(file_path, |output| copy_plaintext(&mut reader, output))
+    }
+
+    /// Authenticates the whole of `path` without writing the plaintext
+    /// anywhere.
+    pub fn verify(&self, path: &Path, progress: &impl Progress) -> Result<()> {
+        copy_plaintext(&mut self.open(path, progress)?, &mut io::sink())
+    }
+
+    /// Decrypts the start of `path` into memory for a preview.
+    pub fn preview(&self, path: &Path, limit: u64) -> Result<String> {
+        let mut plaintext = Zeroizing::new(Vec::new());
+        copy_plaintext(&mut self.open(path, &())?.take(limit), &mut *plaintext)?;
+        Ok(String::from_utf8_lossy(&plaintext).into_owned())
+    }
+
+    /// Unlocks `path` with the key source, returning a reader over its
+    /// plaintext.
+    fn open<'a>(&self, path: &Path, progress: &'a impl Progress) -> Result<impl Read + 'a> {
+        let identities: Vec<Box<dyn Identity>> = match &self.source {
+            KeySource::Passphrase(passphrase) => vec![Box::new(scrypt::Identity::new(
+                SecretString::from(passphrase.to_string()),
+            ))],
+            KeySource::Identity(identity) => identity_file(identity)?
+                .into_identities()
+                .map_err(decrypt_error)?,
+            _ => return Err(CryptoError::WrongKeyType("a passphrase or an age identity").into()),
+        };
+
+        let file = File::open(path)?;
+        progress.total(file.metadata()?.len());
+        let decryptor = Decryptor::new_buffered(BufReader::new(Tracked::new(file, progress)))
+            .map_err(decrypt_error)?;
+        decryptor
+            .decrypt(identities.iter().map(|identity| identity.as_ref()))
+            .map_err(decrypt_error)
+    }
+}
+
+fn copy_plaintext(reader: &mut impl Read, output: &mut impl Write) -> Result<()> {
+    io::copy(reader, output)
+        .map(|_| ())
+        .map_err(|error| match error.kind() {
+            // age reports a segment that fails to authenticate as bare
+            // invalid data.
+            ErrorKind::InvalidData => CryptoError::AuthenticationFailed.into(),
+            _ => error,
+        })
+}
+
+/// Whether the age file at `path` was encrypted with a passphrase rather
+/// than to X25519 recipients.
+pub fn needs_passphrase(path: &Path) -> Result<bool> {
+    let decryptor =
+        Decryptor::new_buffered(BufReader::new(File::open(path)?)).map_err(decrypt_error)?;
+    Ok(decryptor.is_scrypt())
+}
+
+fn identity_file(path: &Path) -> Result<IdentityFile<age::NoCallbacks>> {
+    IdentityFile::from_file(path.to_string_lossy().into_owned())
+}
+
+fn encrypt_error(error: EncryptError) -> Error {
+    match error {
+        EncryptError::Io(error) => error,
+        error => Error::new(ErrorKind::InvalidInput, error.to_string()),
+    }
+}
+
+fn decrypt_error(error: DecryptError) -> Error {
+    match error {
+        DecryptError::Io(error) => error,
+        DecryptError::DecryptionFailed
+        | DecryptError::KeyDecryptionFailed
+        | DecryptError::NoMatchingKeys
+        | DecryptError::InvalidMac => CryptoError::AuthenticationFailed.into(),
+        DecryptError::UnknownFormat => CryptoError::NotEncrypted.into(),
+        error => CryptoError::Malformed(error.to_string()).into(),
+    }
+}
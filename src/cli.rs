@@ -0,0 +1,252 @@
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+};
+
+use clap::{Parser, Subcommand};
+
+use crate::{
+    age_file::AgeEncryptor,
+    config::Config,
+    crypto_handler::{detect_format, random_name, read_header, AesEncryptor, CryptoError, Format},
+    key_source::KeySource,
+};
+
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Read the encryption key from FILE (32 bytes or 64 hex digits)
+    #[arg(long, value_name = "FILE", conflicts_with = "key_env")]
+    pub key_file: Option<PathBuf>,
+
+    /// Read the encryption key from the environment variable VAR (64 hex digits)
+    #[arg(long, value_name = "VAR")]
+    pub key_env: Option<String>,
+
+    /// Encrypt to the X25519 public keys (age1...) listed in FILE
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["key_file", "key_env"])]
+    pub recipients: Option<PathBuf>,
+
+    /// Read X25519 identities (AGE-SECRET-KEY-1...) from FILE
+    #[arg(long, value_name = "FILE")]
+    pub identity: Option<PathBuf>,
+
+    /// Use FILE instead of the default config file
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Encrypt a file or directory without starting the explorer
+    Encrypt {
+        path: PathBuf,
+        /// Defaults to PATH with `.enc` (or `.age`) appended, next to it
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Write an age file instead
+        #[arg(long)]
+        age: bool,
+        /// Give the output a random name and seal the real one inside
+        #[arg(long, conflicts_with = "age")]
+        hide_name: bool,
+    },
+    /// Decrypt a file without starting the explorer
+    Decrypt {
+        path: PathBuf,
+        /// Defaults to PATH without its `.enc` or `.age` suffix, or to the
+        /// name sealed inside
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Replace the output if it already exists
+        #[arg(short, long)]
+        force: bool,
+        /// Open a file made by older versions with the built-in legacy key
+        #[arg(long)]
+        legacy: bool,
+    },
+    /// Check that an encrypted file is intact without writing its plaintext
+    Verify {
+        path: PathBuf,
+        /// Check a file made by older versions with the built-in legacy key
+        #[arg(long)]
+        legacy: bool,
+    },
+}
+
+impl Cli {
+    /// Loads the config file and lets the key options given on the command
+    /// line override it.
+    pub fn config(&self) -> Result<Config> {
+        let mut config = Config::load(self.config.as_deref())?;
+        if self.key_file.is_some() || self.key_env.is_some() || self.recipients.is_some() {
+            config.encryption.key_file = self.key_file.clone();
+            config.encryption.key_env = self.key_env.clone();
+            config.encryption.recipients = self.recipients.clone();
+        }
+        if self.identity.is_some() {
+            config.encryption.identity = self.identity.clone();
+        }
+        Ok(config)
+    }
+}
+
+impl Command {
+    pub fn run(self, config: &Config) -> Result<()> {
+        let cipher = config.encryption.cipher;
+        match self {
+            Command::Encrypt {
+                path,
+                output,
+                age,
+                hide_name,
+            } => {
+                let format = if age { Format::Age } else { Format::FileScout };
+                let hide_name = hide_name || (!age && config.encryption.hide_names);
+                // `.` and `..` have no name of their own, and outputs made
+                // from them would land inside the directory being archived.
+                let path = match path.file_name() {
+                    Some(_) => path,
+                    None => fs::canonicalize(&path)?,
+                };
+                let name = path.file_name().ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput, "PATH has no name, pass --output")
+                })?;
+                let output = output.unwrap_or_else(|| {
+                    if hide_name {
+                        return path.with_file_name(random_name());
+                    }
+                    let mut output = name.to_os_string();
+                    output.push(format.extension());
+                    path.with_file_name(output)
+                });
+                if age {
+                    return AgeEncryptor::new(age_source(config)?).encrypt_file(
+                        &path,
+                        &output,
+                        &(),
+                    );
+                }
+                let encryptor =
+                    AesEncryptor::new(key_source(config)?, cipher).hide_names(hide_name);
+                if path.is_dir() {
+                    encryptor.encrypt_dir(&path, &output, &())?;
+                } else {
+                    encryptor.encrypt_file(&path, &output, &())?;
+                }
+                if hide_name {
+                    println!("{}", output.display());
+                }
+                Ok(())
+            }
+            Command::Decrypt {
+                path,
+                output,
+                force,
+                legacy,
+            } => {
+                let format = input_format(&path, legacy)?;
+                let sealed = format == Format::FileScout && read_header(&path)?.has_sealed_name();
+                if output.is_none() && sealed {
+                    let source = opening_source(config, &path, format)?;
+                    let dir = path.parent().unwrap_or(Path::new(""));
+                    let output =
+                        AesEncryptor::new(source, cipher).decrypt_into(&path, dir, force, &())?;
+                    println!("{}", output.display());
+                    return Ok(());
+                }
+                let output = match output {
+                    Some(output) => output,
+                    None => path
+                        .to_str()
+                        .and_then(|path| path.strip_suffix(format.extension()))
+                        .map(PathBuf::from)
+                        .ok_or_else(|| {
+                            Error::new(
+                                ErrorKind::InvalidInput,
+                                format!(
+                                    "PATH doesn't end in {}, pass --output",
+                                    format.extension()
+                                ),
+                            )
+                        })?,
+                };
+                let source = opening_source(config, &path, format)?;
+                match format {
+                    Format::Age => {
+                        AgeEncryptor::new(source).decrypt_file(&path, &output, force, &())
+                    }
+                    Format::FileScout | Format::Legacy => {
+                        AesEncryptor::new(source, cipher).decrypt_file(&path, &output, force, &())
+                    }
+                }
+            }
+            Command::Verify { path, legacy } => {
+                let format = input_format(&path, legacy)?;
+                let source = opening_source(config, &path, format)?;
+                match format {
+                    Format::Age => AgeEncryptor::new(source).verify(&path, &()),
+                    Format::FileScout | Format::Legacy => AesEncryptor::new(source, cipher)
+                        .verify(&path, &())
+                        .map(|_| ()),
+                }?;
+                println!("{}: OK", path.display());
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Legacy files have no header to be recognised by, so they are only
+/// taken for one when asked to.
+fn input_format(path: &Path, legacy: bool) -> Result<Format> {
+    if legacy {
+        Ok(Format::Legacy)
+    } else {
+        detect_format(path)
+    }
+}
+
+/// The configured key matching how `path` was encrypted.
+fn opening_source(config: &Config, path: &Path, format: Format) -> Result<KeySource> {
+    match format {
+        Format::Legacy => Ok(KeySource::Legacy),
+        Format::Age => config.encryption.identity_source().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "--identity is needed for age files outside the explorer",
+            )
+        }),
+        Format::FileScout => {
+            let kdf = read_header(path)?.kdf;
+            config
+                .encryption
+                .source_for(&kdf)
+                .ok_or_else(|| CryptoError::WrongKeyType(kdf.key_name()).into())
+        }
+    }
+}
+
+/// Outside the explorer there is nobody to ask for a passphrase, so a key
+/// has to be configured.
+fn key_source(config: &Config) -> Result<KeySource> {
+    config.encryption.key_source().ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "--key-file, --key-env or --recipients is needed outside the explorer",
+        )
+    })
+}
+
+fn age_source(config: &Config) -> Result<KeySource> {
+    config.encryption.age_source().ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "--recipients or --identity is needed for age files outside the explorer",
+        )
+    })
+}
@@ -0,0 +1,139 @@
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    constant::LOCK_AFTER,
+    header::{Cipher, Kdf},
+    key_source::KeySource,
+    sort::{Sort, SortKey},
+};
+
+/// Settings read from `config.toml` in the FileScout config directory.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub encryption: EncryptionConfig,
+    pub listing: ListingConfig,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ListingConfig {
+    /// Order directories are listed in until changed with `s`.
+    pub sort: SortKey,
+    /// List in descending order instead.
+    pub descending: bool,
+    /// List dotfiles until toggled with `H`.
+    pub show_hidden: bool,
+    /// Leave out files ignored by `.gitignore` and `.ignore` files until
+    /// toggled with `I`.
+    pub respect_ignore: bool,
+    /// Globs matched against file names that are never listed.
+    pub exclude: Vec<String>,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct EncryptionConfig {
+    /// Raw key file used instead of prompting for a passphrase.
+    pub key_file: Option<PathBuf>,
+    /// Environment variable holding a raw key, used instead of prompting.
+    pub key_env: Option<String>,
+    /// Cipher new files are encrypted with.
+    pub cipher: Cipher,
+    /// Public keys new files are encrypted to instead of a passphrase.
+    pub recipients: Option<PathBuf>,
+    /// X25519 identities, tried on age files and files encrypted to
+    /// recipients.
+    pub identity: Option<PathBuf>,
+    /// Give new `.enc` files random names and seal the real one inside.
+    pub hide_names: bool,
+    /// Seconds without a key press before a remembered passphrase is
+    /// forgotten, 0 to keep it until FileScout quits.
+    pub lock_after: Option<u64>,
+}
+
+impl Config {
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("filescout").join("config.toml"))
+    }
+
+    /// Loads `path`, or the default location when it is `None`. A missing
+    /// default config just means the defaults apply.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let content = match path {
+            Some(path) => fs::read_to_string(path)?,
+            None => match Self::default_path().map(fs::read_to_string) {
+                Some(Ok(content)) => content,
+                Some(Err(error)) if error.kind() != ErrorKind::NotFound => return Err(error),
+                _ => return Ok(Self::default()),
+            },
+        };
+        toml::from_str(&content).map_err(|error| Error::new(ErrorKind::InvalidData, error))
+    }
+}
+
+impl ListingConfig {
+    pub fn sort(&self) -> Sort {
+        Sort {
+            key: self.sort,
+            descending: self.descending,
+        }
+    }
+}
+
+impl EncryptionConfig {
+    /// The configured key source for new files, or `None` when a passphrase
+    /// should be asked for.
+    pub fn key_source(&self) -> Option<KeySource> {
+        if let Some(path) = &self.key_file {
+            Some(KeySource::KeyFile(path.to_path_buf()))
+        } else if let Some(name) = &self.key_env {
+            Some(KeySource::Env(name.to_string()))
+        } else {
+            self.recipients.clone().map(KeySource::Recipients)
+        }
+    }
+
+    /// The configured key source able to open a file whose key was derived
+    /// with `kdf`, or `None` when it needs a passphrase or nothing fitting
+    /// is configured.
+    pub fn source_for(&self, kdf: &Kdf) -> Option<KeySource> {
+        match kdf {
+            Kdf::Argon2id { .. } => None,
+            Kdf::Raw { .. } => self
+                .key_file
+                .clone()
+                .map(KeySource::KeyFile)
+                .or_else(|| self.key_env.clone().map(KeySource::Env)),
+            Kdf::X25519 { .. } => self.identity_source(),
+        }
+    }
+
+    /// The key source for new age files: the recipients, or else the
+    /// identities' own public keys.
+    pub fn age_source(&self) -> Option<KeySource> {
+        self.recipients
+            .clone()
+            .map(KeySource::Recipients)
+            .or_else(|| self.identity_source())
+    }
+
+    /// How long the session stays unlocked while idle, `None` for ever.
+    pub fn lock_timeout(&self) -> Option<Duration> {
+        match self.lock_after.unwrap_or(LOCK_AFTER) {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    }
+
+    pub fn identity_source(&self) -> Option<KeySource> {
+        self.identity.clone().map(KeySource::Identity)
+    }
+}
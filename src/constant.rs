@@ -12,4 +12,13 @@ pub const COLORS: [(Color, Color); 9] = [
     (Color::LightBlue, Color::DarkGray),
 ];
 
+/// Most plaintext decrypted for a preview. Each segment is authenticated on
+/// its own, so a prefix can be shown without reading the whole file.
+pub const PREVIEW_LIMIT: u64 = 1024 * 1024;
+
+/// Seconds the session stays unlocked without a key press, unless the
+/// config says otherwise.
+pub const LOCK_AFTER: u64 = 5 * 60;
+
+/// Key used by FileScout before passphrases, kept for `KeySource::Legacy`.
 pub const KEY: &str = "mY2$3GzK!7a9VbQjL1^XwFc%rLp0tZde";
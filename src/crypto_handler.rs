@@ -1,56 +1,1228 @@
 use std::{
-    fs::File,
-    io::{Read, Result, Write},
-    path::Path,
+    fmt::{self, Display, Formatter},
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Error, ErrorKind, Read, Result, Write},
+    path::{Component, Path, PathBuf},
 };
 
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+
 use aes_gcm::{
-    aead::{Aead, OsRng},
-    AeadCore, Aes256Gcm, KeyInit, Nonce,
+    aead::{rand_core::RngCore, Aead, OsRng},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
+use filetime::FileTime;
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use tar::{Archive, Builder};
+use zeroize::Zeroizing;
+
+use crate::{
+    age_file::AGE_MAGIC,
+    constant::KEY,
+    header::{Cipher, Header, Kdf, Metadata, EXT_ARCHIVE, EXT_METADATA, EXT_SEALED_NAME, MAGIC},
+    jobs::{Progress, Tracked},
+    key_source::{KeySource, KEY_LEN},
+    recipient::{data_key, read_identities, read_recipients, unwrap_key, wrap_key},
+    stream::{DecryptReader, EncryptWriter, SegmentCipher, SEGMENT_SIZE, TAG_LEN},
 };
 
-use crate::constant::KEY;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+/// HKDF info string binding raw keys to their use for file encryption.
+const FILE_KEY_INFO: &[u8] = b"filescout file key";
+/// HKDF info string for the key sealing a file's name.
+const NAME_KEY_INFO: &[u8] = b"filescout file name";
+/// Largest segment a header may ask for, so a damaged file can't make us
+/// allocate an arbitrary amount of memory.
+const MAX_SEGMENT_SIZE: u32 = 16 * 1024 * 1024;
+/// Most Argon2id memory a header may ask for, in KiB. Like the limits below,
+/// it keeps a damaged file from exhausting memory or time before its header
+/// is authenticated.
+const MAX_M_COST: u32 = 1024 * 1024;
+const MAX_T_COST: u32 = 16;
+const MAX_P_COST: u32 = 16;
 
-pub struct AesEncryptor<'a> {
-    key: &'a [u8],
+/// Reasons an encrypted file can't be opened. They travel inside an
+/// `io::Error` so they end up in `FileStruct::error` like any other failure.
+#[derive(Clone, Debug)]
+pub enum CryptoError {
+    NotEncrypted,
+    Truncated,
+    AuthenticationFailed,
+    UnsupportedVersion(u8),
+    Malformed(String),
+    /// The file was encrypted with a different kind of key than was given.
+    WrongKeyType(&'static str),
+    /// The file is encrypted to public keys, none of them ours.
+    NoMatchingIdentity,
 }
 
-impl AesEncryptor<'static> {
-    pub const fn new() -> Self {
-        let key = KEY;
-        let key = key.as_bytes();
-        Self { key }
+impl Display for CryptoError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            CryptoError::NotEncrypted => write!(f, "Not a FileScout encrypted file"),
+            CryptoError::Truncated => write!(f, "Encrypted file is truncated"),
+            CryptoError::AuthenticationFailed => write!(f, "Wrong key or corrupted file"),
+            CryptoError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported .enc format version {version}")
+            }
+            CryptoError::Malformed(reason) => write!(f, "Malformed encrypted file: {reason}"),
+            CryptoError::WrongKeyType(needed) => write!(f, "This file needs {needed}"),
+            CryptoError::NoMatchingIdentity => {
+                write!(f, "None of your identities can open this file")
+            }
+        }
     }
+}
+
+impl std::error::Error for CryptoError {}
+
+impl CryptoError {
+    /// The reason carried by `error`, if it is one of these.
+    pub fn of(error: &Error) -> Option<&CryptoError> {
+        error.get_ref()?.downcast_ref()
+    }
+}
+
+/// Whether `error` means the key didn't fit the file, or the file was
+/// tampered with.
+pub fn is_wrong_key(error: &Error) -> bool {
+    matches!(
+        CryptoError::of(error),
+        Some(CryptoError::AuthenticationFailed)
+    )
+}
+
+/// Reads the header of `path` without needing a key, to tell encrypted
+/// files apart from everything else.
+pub fn read_header(path: &Path) -> Result<Header> {
+    Header::read_from(&mut BufReader::new(File::open(path)?))
+}
+
+/// Container formats an encrypted file can be in.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    FileScout,
+    /// age v1, see `age_file.rs`.
+    Age,
+    /// Headerless files under the built-in key, from before passphrases.
+    /// They can't be told apart from random data, so this is never detected.
+    Legacy,
+}
+
+impl Format {
+    /// Suffix given to files encrypted in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::FileScout | Format::Legacy => ".enc",
+            Format::Age => ".age",
+        }
+    }
+}
+
+/// Works out the format of `path` from its first bytes.
+pub fn detect_format(path: &Path) -> Result<Format> {
+    let mut start = Vec::new();
+    File::open(path)?
+        .take(AGE_MAGIC.len() as u64)
+        .read_to_end(&mut start)?;
+    if start.starts_with(MAGIC) {
+        Ok(Format::FileScout)
+    } else if start == AGE_MAGIC {
+        Ok(Format::Age)
+    } else {
+        Err(CryptoError::NotEncrypted.into())
+    }
+}
+
+/// Fails if `path` exists, unless the user agreed to `overwrite` it.
+pub fn check_output(path: &Path, overwrite: bool) -> Result<()> {
+    if !overwrite && fs::symlink_metadata(path).is_ok() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} already exists", path.display()),
+        ));
+    }
+    Ok(())
+}
+
+/// tar wraps read errors in its own message. Digs a `CryptoError` back out so
+/// the status bar says what actually went wrong.
+fn crypto_cause(error: Error) -> Error {
+    let mut source = error
+        .get_ref()
+        .map(|inner| inner as &(dyn std::error::Error + 'static));
+    while let Some(inner) = source {
+        if let Some(crypto) = inner.downcast_ref::<CryptoError>() {
+            return crypto.clone().into();
+        }
+        source = match inner.downcast_ref::<Error>() {
+            Some(io_error) => io_error.get_ref().map(|inner| inner as _),
+            None => inner.source(),
+        };
+    }
+    error
+}
+
+impl From<CryptoError> for Error {
+    fn from(error: CryptoError) -> Self {
+        let kind = match error {
+            CryptoError::Truncated => ErrorKind::UnexpectedEof,
+            _ => ErrorKind::InvalidData,
+        };
+        Error::new(kind, error)
+    }
+}
+
+/// Stretches `secret` into a file key bound to `salt`.
+fn expand_key(salt: &[u8], secret: &[u8]) -> Result<Zeroizing<[u8; KEY_LEN]>> {
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    Hkdf::<Sha256>::new(Some(salt), secret)
+        .expand(FILE_KEY_INFO, key.as_mut())
+        .map_err(|_| Error::other("Key derivation failed"))?;
+    Ok(key)
+}
+
+fn segment_cipher(cipher: Cipher, key: &[u8; KEY_LEN]) -> SegmentCipher {
+    let key = key.into();
+    match cipher {
+        Cipher::Aes256Gcm => SegmentCipher::Aes256Gcm(Box::new(Aes256Gcm::new(key))),
+        Cipher::ChaCha20Poly1305 => SegmentCipher::ChaCha20Poly1305(ChaCha20Poly1305::new(key)),
+        Cipher::XChaCha20Poly1305 => SegmentCipher::XChaCha20Poly1305(XChaCha20Poly1305::new(key)),
+    }
+}
+
+fn name_cipher(key: &[u8; KEY_LEN]) -> Result<ChaCha20Poly1305> {
+    let mut name_key = Zeroizing::new([0u8; KEY_LEN]);
+    Hkdf::<Sha256>::new(None, key)
+        .expand(NAME_KEY_INFO, name_key.as_mut())
+        .map_err(|_| Error::other("Key derivation failed"))?;
+    Ok(ChaCha20Poly1305::new(name_key.as_ref().into()))
+}
+
+fn seal_name(name: &str, key: &[u8; KEY_LEN]) -> Result<Vec<u8>> {
+    let mut sealed = vec![0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut sealed);
+    let ciphertext = name_cipher(key)?
+        .encrypt(Nonce::from_slice(&sealed), name.as_bytes())
+        .map_err(|_| Error::other("Encryption failed"))?;
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+fn unseal_name(sealed: &[u8], key: &[u8; KEY_LEN]) -> Result<String> {
+    if sealed.len() < NONCE_LEN {
+        return Err(CryptoError::Malformed(String::from("bad sealed name")).into());
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let name = name_cipher(key)?
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| CryptoError::AuthenticationFailed)?;
+    String::from_utf8(name)
+        .map_err(|_| CryptoError::Malformed(String::from("bad sealed name")).into())
+}
 
-    pub fn decrypt_file(&self, path: &Path, file_path: &Path) -> Result<()> {
-        let mut file = File::open(path)?;
+/// An opaque name for an encrypted file whose real name is sealed inside.
+pub fn random_name() -> String {
+    let mut bytes = [0u8; 12];
+    OsRng.fill_bytes(&mut bytes);
+    let mut name: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    name.push_str(Format::FileScout.extension());
+    name
+}
+
+type PlaintextReader<'a, P> = DecryptReader<Tracked<'a, BufReader<File>, P>>;
+
+pub struct AesEncryptor {
+    source: KeySource,
+    /// Cipher new files are encrypted with. Decryption uses the one recorded
+    /// in the file's header.
+    cipher: Cipher,
+    /// Seal the names of new files inside them.
+    hide_names: bool,
+}
+
+impl AesEncryptor {
+    pub fn new(source: KeySource, cipher: Cipher) -> Self {
+        Self {
+            source,
+            cipher,
+            hide_names: false,
+        }
+    }
+
+    pub fn hide_names(mut self, hide_names: bool) -> Self {
+        self.hide_names = hide_names;
+        self
+    }
+
+    /// Derives this file's key from the key source and the salt in `header`.
+    fn file_key(&self, header: &Header) -> Result<Zeroizing<[u8; KEY_LEN]>> {
+        match (&self.source, &header.kdf) {
+            (KeySource::Legacy, _) => Err(Error::new(
+                ErrorKind::Unsupported,
+                "Legacy key can't open this file",
+            )),
+            (
+                KeySource::Passphrase(passphrase),
+                Kdf::Argon2id {
+                    m_cost,
+                    t_cost,
+                    p_cost,
+                    salt,
+                },
+            ) => {
+                if *m_cost > MAX_M_COST || *t_cost > MAX_T_COST || *p_cost > MAX_P_COST {
+                    return Err(
+                        CryptoError::Malformed(String::from("bad key derivation cost")).into(),
+                    );
+                }
+                let params = Params::new(*m_cost, *t_cost, *p_cost, Some(KEY_LEN))
+                    .map_err(|error| CryptoError::Malformed(error.to_string()))?;
+                let mut key = Zeroizing::new([0u8; KEY_LEN]);
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+                    .map_err(|error| Error::new(ErrorKind::InvalidInput, error.to_string()))?;
+                Ok(key)
+            }
+            (KeySource::KeyFile(_) | KeySource::Env(_), Kdf::Raw { salt }) => {
+                expand_key(salt, self.source.raw_key()?.as_ref())
+            }
+            (KeySource::Identity(path), Kdf::X25519 { salt, recipients }) => {
+                let data_key = unwrap_key(recipients, &read_identities(path)?)?;
+                expand_key(salt, data_key.as_ref())
+            }
+            (_, kdf) => Err(CryptoError::WrongKeyType(kdf.key_name()).into()),
+        }
+    }
+
+    /// Reads and checks the header of `path`, returning a reader that
+    /// yields the authenticated plaintext.
+    fn open<'a, P: Progress>(
+        &self,
+        path: &Path,
+        progress: &'a P,
+    ) -> Result<(Header, PlaintextReader<'a, P>)> {
+        Self::open_with(path, progress, |header| self.file_key(header))
+    }
+
+    /// Like `open`, with the file key coming from `key` rather than the key
+    /// source. A sealed name is unsealed into the returned header's `name`.
+    fn open_with<'a, P: Progress>(
+        path: &Path,
+        progress: &'a P,
+        key: impl FnOnce(&Header) -> Result<Zeroizing<[u8; KEY_LEN]>>,
+    ) -> Result<(Header, PlaintextReader<'a, P>)> {
+        let file = File::open(path)?;
+        progress.total(file.metadata()?.len());
+        let mut reader = BufReader::new(file);
+        let header = Header::read_from(&mut reader)?;
+        if header.nonce.len() != header.cipher.nonce_prefix_len() {
+            return Err(CryptoError::Malformed(String::from("bad nonce length")).into());
+        }
+        if header.segment_size == 0 || header.segment_size > MAX_SEGMENT_SIZE {
+            return Err(CryptoError::Malformed(String::from("bad segment size")).into());
+        }
+        // An archive's size is only the sum of the packed files, not the
+        // length of the tar stream.
+        let expected = (!header.is_archive()).then_some(header.size);
+        let key = key(&header)?;
+        let reader = DecryptReader::new(
+            Tracked::new(reader, progress),
+            segment_cipher(header.cipher, &key),
+            &header.nonce,
+            header.to_bytes(),
+            header.segment_size as usize,
+            expected,
+        );
+        if let Some(sealed) = header.extension(EXT_SEALED_NAME) {
+            let name = unseal_name(sealed, &key)?;
+            return Ok((Header { name, ..header }, reader));
+        }
+        Ok((header, reader))
+    }
+
+    /// Decrypts `path` into `file_path`, unpacking it as a directory when it
+    /// holds an archive. The output only appears at `file_path` once
+    /// everything has been authenticated. An existing `file_path` is only
+    /// replaced when `overwrite` is set.
+    pub fn decrypt_file(
+        &self,
+        path: &Path,
+        file_path: &Path,
+        overwrite: bool,
+        progress: &impl Progress,
+    ) -> Result<()> {
+        check_output(file_path, overwrite)?;
+        if let KeySource::Legacy = self.source {
+            let plaintext = Self::decrypt_legacy(&mut File::open(path)?)?;
+            return write_atomic(file_path, |output| output.write_all(&plaintext));
+        }
+
+        let (header, mut reader) = self.open(path, progress)?;
+        let metadata = header.metadata()?;
+        if !header.is_archive() {
+            write_atomic(file_path, |output| {
+                io::copy(&mut reader, output).map(|_| ())
+            })?;
+            return metadata.map_or(Ok(()), |metadata| restore_metadata(file_path, &metadata));
+        }
+
+        unpack_atomic(file_path, |dir| {
+            let mut archive = Archive::new(reader);
+            archive.set_preserve_permissions(true);
+            // Like `restore_metadata`, setuid and similar bits aren't kept.
+            archive.set_mask(0o7000);
+            archive.set_preserve_mtime(true);
+            // Directories are restored last, so read-only ones can still be
+            // filled and adding their contents doesn't bump their mtime.
+            let mut dirs = Vec::new();
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                progress.entry(&entry.path()?);
+                if entry.header().entry_type().is_dir() {
+                    dirs.push(entry);
+                } else {
+                    entry.unpack_in(dir)?;
+                }
+            }
+            for mut entry in dirs.into_iter().rev() {
+                // tar skips entries that climb out of `dir` with `..`.
+                if !entry.unpack_in(dir)? {
+                    continue;
+                }
+                // tar leaves directory mtimes alone, so restore them here.
+                // Absolute paths were unpacked below `dir`, and are joined
+                // the same way.
+                let relative: PathBuf = entry
+                    .path()?
+                    .components()
+                    .filter(|component| matches!(component, Component::Normal(_)))
+                    .collect();
+                let mtime = FileTime::from_unix_time(entry.header().mtime()? as i64, 0);
+                filetime::set_file_mtime(dir.join(relative), mtime)?;
+            }
+            // tar stops at its end-of-archive marker; the rest of the stream
+            // still has to be read for the final segment to be authenticated.
+            io::copy(&mut archive.into_inner(), &mut io::sink())?;
+            Ok(())
+        })
+        .map_err(crypto_cause)?;
+        metadata.map_or(Ok(()), |metadata| restore_metadata(file_path, &metadata))
+    }
+
+    /// Decrypts `path` into `dir` under the name sealed inside it, returning
+    /// where it went. An existing file is only replaced when `overwrite` is
+    /// set.
+    pub fn decrypt_into(
+        &self,
+        path: &Path,
+        dir: &Path,
+        overwrite: bool,
+        progress: &impl Progress,
+    ) -> Result<PathBuf> {
+        let (header, _) = self.open(path, &())?;
+        let name = sealed_file_name(&header)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "File has no sealed name"))?;
+        let output = dir.join(name);
+        self.decrypt_file(path, &output, overwrite, progress)?;
+        Ok(output)
+    }
+
+    /// Authenticates every segment of `path` without writing the plaintext
+    /// anywhere, so backups can be checked in place. Returns the name sealed
+    /// inside, if there is one.
+    pub fn verify(&self, path: &Path, progress: &impl Progress) -> Result<Option<String>> {
+        if let KeySource::Legacy = self.source {
+            return Self::decrypt_legacy(&mut File::open(path)?).map(|_| None);
+        }
+        let (header, mut reader) = self.open(path, progress)?;
+        io::copy(&mut reader, &mut io::sink())?;
+        Ok(sealed_file_name(&header).map(str::to_owned))
+    }
+
+    /// The name sealed inside `path`, if it has one. Only the header is
+    /// read, so this takes little more than deriving the key.
+    pub fn sealed_name(&self, path: &Path) -> Result<Option<String>> {
+        let (header, _) = self.open(path, &())?;
+        Ok(sealed_file_name(&header).map(str::to_owned))
+    }
+
+    /// Files from before the header existed are a bare nonce followed by
+    /// ciphertext under `constant::KEY`.
+    fn decrypt_legacy(file: &mut File) -> Result<Vec<u8>> {
         let mut encrypted_data = Vec::new();
         file.read_to_end(&mut encrypted_data)?;
+        if encrypted_data.len() < NONCE_LEN + TAG_LEN {
+            return Err(CryptoError::Truncated.into());
+        }
+
+        let nonce = Nonce::from_slice(&encrypted_data[..NONCE_LEN]);
+        let cipher = Aes256Gcm::new(KEY.as_bytes().into());
+        cipher
+            .decrypt(nonce, &encrypted_data[NONCE_LEN..])
+            .map_err(|_| CryptoError::AuthenticationFailed.into())
+    }
+
+    /// Makes the header for a new file, returning it with the file key.
+    /// With `hide_name` the name is sealed in an extension instead of being
+    /// stored in the clear.
+    fn new_header(
+        &self,
+        name: String,
+        size: u64,
+        extensions: Vec<(u8, Vec<u8>)>,
+        hide_name: bool,
+    ) -> Result<(Header, Zeroizing<[u8; KEY_LEN]>)> {
+        let mut salt = vec![0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let mut nonce = vec![0u8; self.cipher.nonce_prefix_len()];
+        OsRng.fill_bytes(&mut nonce);
+        // Only the recipients' identities can derive the key of a file
+        // encrypted to them, so it is kept from here.
+        let mut key = None;
+        let kdf = match &self.source {
+            KeySource::Passphrase(_) | KeySource::Legacy => {
+                let params = Params::default();
+                Kdf::Argon2id {
+                    m_cost: params.m_cost(),
+                    t_cost: params.t_cost(),
+                    p_cost: params.p_cost(),
+                    salt,
+                }
+            }
+            KeySource::KeyFile(_) | KeySource::Env(_) => Kdf::Raw { salt },
+            KeySource::Recipients(path) => {
+                let data_key = data_key();
+                key = Some(expand_key(&salt, data_key.as_ref())?);
+                Kdf::X25519 {
+                    salt,
+                    recipients: wrap_key(&data_key, &read_recipients(path)?)?,
+                }
+            }
+            KeySource::Identity(_) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Encrypting needs a recipients file, not an identity",
+                ))
+            }
+        };
+        let header = Header {
+            cipher: self.cipher,
+            kdf,
+            nonce,
+            segment_size: SEGMENT_SIZE as u32,
+            name,
+            size,
+            extensions,
+        };
+        let key = match key {
+            Some(key) => key,
+            None => self.file_key(&header)?,
+        };
+        if hide_name {
+            let sealed = seal_name(&header.name, &key)?;
+            let header = Header {
+                name: String::new(),
+                extensions: [(EXT_SEALED_NAME, sealed)]
+                    .into_iter()
+                    .chain(header.extensions)
+                    .collect(),
+                ..header
+            };
+            return Ok((header, key));
+        }
+        Ok((header, key))
+    }
+
+    pub fn encrypt_file(&self, path: &Path, output: &Path, progress: &impl Progress) -> Result<()> {
+        self.encrypt_file_digest(path, output, progress).map(|_| ())
+    }
+
+    /// Encrypts `path` into `output`, returning the SHA-256 of the plaintext
+    /// that went in and the file key.
+    fn encrypt_file_digest(
+        &self,
+        path: &Path,
+        output: &Path,
+        progress: &impl Progress,
+    ) -> Result<([u8; 32], Zeroizing<[u8; KEY_LEN]>)> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let size = metadata.len();
+        progress.total(size);
+        let extensions = vec![(EXT_METADATA, metadata_of(&metadata).to_bytes())];
+        let (header, key) = self.new_header(file_name(path), size, extensions, self.hide_names)?;
+
+        let cipher = segment_cipher(header.cipher, &key);
+        let mut reader = Hashing::new(Tracked::new(file.take(size), progress));
+        write_atomic(output, |output| {
+            header.write_to(output)?;
+            let mut writer = EncryptWriter::new(
+                output,
+                cipher,
+                &header.nonce,
+                header.to_bytes(),
+                SEGMENT_SIZE,
+            );
+            let copied = io::copy(&mut reader, &mut writer)?;
+            if copied != size {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "File changed while encrypting",
+                ));
+            }
+            writer.finish()?;
+            Ok(())
+        })?;
+        Ok((reader.finish(), key))
+    }
+
+    /// Encrypts `path` into `output`, checks that `output` decrypts back to
+    /// the same contents, and only then overwrites `path` with random data
+    /// and removes it.
+    pub fn encrypt_and_remove(
+        &self,
+        path: &Path,
+        output: &Path,
+        progress: &impl Progress,
+    ) -> Result<()> {
+        if !path.is_file() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Only files can be removed after encryption",
+            ));
+        }
+        let (expected, key) = self.encrypt_file_digest(path, output, progress)?;
+        // Checked with the key just used, since a file encrypted to someone
+        // else's public key can't be opened with our own key source.
+        let (_, reader) = Self::open_with(output, progress, |_| Ok(key))?;
+        if digest(reader)? != expected {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Encrypted file didn't verify, original kept",
+            ));
+        }
+        wipe_file(path, progress)
+    }
 
-        let nonce = Nonce::from_slice(&encrypted_data[..12]);
-        let cipher = Aes256Gcm::new_from_slice(self.key).unwrap();
+    /// Decrypts the start of `path` into memory for a preview, never
+    /// touching the disk. Directories are listed instead. Also returns the
+    /// file's real name when it was sealed inside.
+    pub fn preview(&self, path: &Path, limit: u64) -> Result<(Option<String>, String)> {
+        let (header, reader) = self.open(path, &())?;
+        let name = sealed_file_name(&header).map(str::to_owned);
+        if header.is_archive() {
+            let mut listing = String::new();
+            for entry in Archive::new(reader).entries().map_err(crypto_cause)? {
+                let entry = entry.map_err(crypto_cause)?;
+                listing.push_str(&entry.path()?.to_string_lossy());
+                if entry.header().entry_type().is_dir() {
+                    listing.push('/');
+                }
+                listing.push('\n');
+            }
+            return Ok((name, listing));
+        }
+        let mut plaintext = Zeroizing::new(Vec::new());
+        reader.take(limit).read_to_end(&mut plaintext)?;
+        Ok((name, String::from_utf8_lossy(&plaintext).into_owned()))
+    }
 
-        let ciphertext = &encrypted_data[12..];
-        let plaintext = cipher.decrypt(nonce, ciphertext).unwrap();
+    /// Decrypts the text file at `path` into memory for editing.
+    pub fn open_document(
+        &self,
+        path: &Path,
+        progress: &impl Progress,
+    ) -> Result<(EncryptedDocument, String)> {
+        let header = read_header(path)?;
+        if header.is_archive() {
+            return Err(Error::new(
+                ErrorKind::IsADirectory,
+                "Encrypted directories can't be edited",
+            ));
+        }
+        let key = self.file_key(&header)?;
+        // The document keeps the header as stored, with any sealed name
+        // still sealed.
+        let (_, mut reader) = Self::open_with(path, progress, |_| Ok(key.clone()))?;
+        let mut plaintext = Zeroizing::new(Vec::new());
+        reader.read_to_end(&mut plaintext)?;
+        let text = std::str::from_utf8(&plaintext)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Only text files can be edited"))?
+            .to_owned();
+        let document = EncryptedDocument {
+            path: path.to_path_buf(),
+            header,
+            key,
+        };
+        Ok((document, text))
+    }
 
-        let mut file = File::create(file_path)?;
-        file.write_all(&plaintext)?;
-        Ok(())
+    /// Re-encrypts the file at `path` under the key source of `new`. The
+    /// plaintext is streamed into a temporary file, which only replaces
+    /// `path` once every segment of the original has been authenticated.
+    pub fn rekey(&self, path: &Path, new: &AesEncryptor, progress: &impl Progress) -> Result<()> {
+        let (old, mut reader) = self.open(path, progress)?;
+        // A hidden name stays hidden, sealed under the new key.
+        let hide_name = old.has_sealed_name();
+        let extensions = old
+            .extensions
+            .into_iter()
+            .filter(|(tag, _)| *tag != EXT_SEALED_NAME)
+            .collect();
+        let (header, key) = new.new_header(old.name, old.size, extensions, hide_name)?;
+        let cipher = segment_cipher(header.cipher, &key);
+        write_atomic(path, |output| {
+            header.write_to(output)?;
+            let mut writer = EncryptWriter::new(
+                output,
+                cipher,
+                &header.nonce,
+                header.to_bytes(),
+                SEGMENT_SIZE,
+            );
+            io::copy(&mut reader, &mut writer)?;
+            writer.finish()?;
+            Ok(())
+        })
     }
 
-    pub fn encrypt_file(&self, path: &Path, output: &Path) -> Result<()> {
-        let mut file = File::open(path)?;
-        let mut buf = Vec::new();
+    /// Packs the tree under `path` into a tar stream and encrypts it into
+    /// `output`.
+    pub fn encrypt_dir(&self, path: &Path, output: &Path, progress: &impl Progress) -> Result<()> {
+        let size = tree_size(path)?;
+        progress.total(size);
+        let extensions = vec![
+            (EXT_ARCHIVE, Vec::new()),
+            (EXT_METADATA, metadata_of(&fs::metadata(path)?).to_bytes()),
+        ];
+        let (header, key) = self.new_header(file_name(path), size, extensions, self.hide_names)?;
 
-        file.read_to_end(&mut buf)?;
+        let cipher = segment_cipher(header.cipher, &key);
+        write_atomic(output, |output| {
+            header.write_to(output)?;
+            let writer = EncryptWriter::new(
+                output,
+                cipher,
+                &header.nonce,
+                header.to_bytes(),
+                SEGMENT_SIZE,
+            );
+            let mut builder = Builder::new(Tracked::new(writer, progress));
+            builder.follow_symlinks(false);
+            append_tree(&mut builder, path, path, progress)?;
+            builder.into_inner()?.into_inner().finish()?;
+            Ok(())
+        })
+    }
+}
 
-        let cipher = Aes256Gcm::new_from_slice(self.key).unwrap();
-        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-        let ciphertext = cipher.encrypt(&nonce, buf.as_ref()).unwrap();
+/// An encrypted file open in the editor, with its key so it can be written
+/// back without asking for it again.
+pub struct EncryptedDocument {
+    path: PathBuf,
+    header: Header,
+    key: Zeroizing<[u8; KEY_LEN]>,
+}
+
+impl EncryptedDocument {
+    /// Encrypts `text` back into the file under the same key and key
+    /// derivation, so passphrases and recipients stay as they were, but with
+    /// a fresh nonce. The plaintext only ever goes to the temporary file
+    /// encrypted.
+    pub fn save(&self, text: &str) -> Result<()> {
+        let mut nonce = vec![0u8; self.header.cipher.nonce_prefix_len()];
+        OsRng.fill_bytes(&mut nonce);
+        let mut header = Header {
+            nonce,
+            segment_size: SEGMENT_SIZE as u32,
+            size: text.len() as u64,
+            ..self.header.clone()
+        };
+        // The contents change now, whenever the original was last modified.
+        if let Some(mut metadata) = header.metadata()? {
+            metadata.modified = FileTime::now();
+            header.set_extension(EXT_METADATA, metadata.to_bytes());
+        }
+        write_atomic(&self.path, |output| {
+            header.write_to(output)?;
+            let mut writer = EncryptWriter::new(
+                output,
+                segment_cipher(header.cipher, &self.key),
+                &header.nonce,
+                header.to_bytes(),
+                SEGMENT_SIZE,
+            );
+            writer.write_all(text.as_bytes())?;
+            writer.finish()?;
+            Ok(())
+        })
+    }
+}
+
+fn metadata_of(metadata: &fs::Metadata) -> Metadata {
+    #[cfg(unix)]
+    let mode = metadata.permissions().mode() & 0o777;
+    #[cfg(not(unix))]
+    let mode = if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    };
+    Metadata {
+        mode,
+        modified: FileTime::from_last_modification_time(metadata),
+        accessed: FileTime::from_last_access_time(metadata),
+    }
+}
+
+/// Puts back the permissions and timestamps recorded by `metadata_of`.
+/// Setuid and similar bits aren't kept, so a decrypted file can't gain them.
+fn restore_metadata(path: &Path, metadata: &Metadata) -> Result<()> {
+    filetime::set_file_times(path, metadata.accessed, metadata.modified)?;
+    #[cfg(unix)]
+    let permissions = fs::Permissions::from_mode(metadata.mode & 0o777);
+    #[cfg(not(unix))]
+    let permissions = {
+        let mut permissions = fs::metadata(path)?.permissions();
+        permissions.set_readonly(metadata.mode & 0o200 == 0);
+        permissions
+    };
+    fs::set_permissions(path, permissions)
+}
+
+/// The name sealed in an opened `header`, if it names a file. It comes from
+/// the file, so only its last component is used, and only when that is a
+/// plain name rather than nothing, `.` or `..`.
+fn sealed_file_name(header: &Header) -> Option<&str> {
+    if !header.has_sealed_name() {
+        return None;
+    }
+    match Path::new(&header.name).components().next_back()? {
+        Component::Normal(name) => name.to_str(),
+        _ => None,
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// The FileScout files at `path`, or anywhere under it when it is a
+/// directory, with how their keys are derived. Files below a directory whose
+/// header can't be read are left out. Symlinks aren't followed.
+pub fn encrypted_files(path: &Path, progress: &impl Progress) -> Result<Vec<(PathBuf, Kdf)>> {
+    if !fs::symlink_metadata(path)?.is_dir() {
+        return Ok(vec![(path.to_path_buf(), read_header(path)?.kdf)]);
+    }
+    let mut entries = fs::read_dir(path)?.collect::<Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    let mut files = Vec::new();
+    for entry in entries {
+        // Stops once the job is cancelled.
+        progress.advance(0)?;
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+        if file_type.is_dir() {
+            files.extend(encrypted_files(&path, progress)?);
+        } else if file_type.is_file() {
+            progress.entry(&path);
+            if let Ok(header) = read_header(&path) {
+                files.push((path, header.kdf));
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn append_tree<W: Write>(
+    builder: &mut Builder<W>,
+    root: &Path,
+    dir: &Path,
+    progress: &impl Progress,
+) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let name = path.strip_prefix(root).unwrap_or(&path);
+        progress.entry(name);
+        builder.append_path_with_name(&path, name)?;
+        if entry.file_type()?.is_dir() {
+            append_tree(builder, root, &path, progress)?;
+        }
+    }
+    Ok(())
+}
+
+fn tree_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            size += tree_size(&entry.path())?;
+        } else if file_type.is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Directory counterpart of `write_atomic`: `unpack` fills a hidden
+/// temporary directory that is renamed to `path` only on success.
+fn unpack_atomic(path: &Path, unpack: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let tmp = temp_path(path);
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    builder.mode(0o700);
+    let result = builder.create(&tmp).and_then(|()| unpack(&tmp));
+    match result.and_then(|()| replace(&tmp, path)) {
+        Ok(()) => Ok(()),
+        Err(error) => {
+            let _ = fs::remove_dir_all(&tmp);
+            Err(error)
+        }
+    }
+}
+
+/// Overwrites the contents of `path` with random data, syncs it to disk and
+/// unlinks it. Copy-on-write and journaling filesystems or SSD wear levelling
+/// may still keep old blocks around, so this is a best effort.
+fn wipe_file(path: &Path, progress: &impl Progress) -> Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let size = file.metadata()?.len();
+    progress.total(size);
+    let mut block = vec![0u8; SEGMENT_SIZE];
+    let mut remaining = size;
+    while remaining > 0 {
+        let len = remaining.min(block.len() as u64) as usize;
+        OsRng.fill_bytes(&mut block[..len]);
+        file.write_all(&block[..len])?;
+        remaining -= len as u64;
+        // Cancelling halfway would leave the original neither intact nor
+        // removed, so the wipe always runs to the end.
+        let _ = progress.advance(len as u64);
+    }
+    file.sync_all()?;
+    drop(file);
+    fs::remove_file(path)
+}
+
+/// Reads `reader` to the end, returning the SHA-256 of what it yielded.
+fn digest(reader: impl Read) -> Result<[u8; 32]> {
+    let mut reader = Hashing::new(reader);
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(reader.finish())
+}
+
+/// Reader that keeps a SHA-256 of everything read through it.
+struct Hashing<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Hashing<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    fn finish(self) -> [u8; 32] {
+        self.hasher.finalize().into()
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Writes into a hidden temporary file next to `path` and only renames it
+/// into place when `write` succeeds, so a failure never leaves a partial file.
+pub fn write_atomic(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<()>,
+) -> Result<()> {
+    let tmp = temp_path(path);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    // Only the owner can read encrypted files, or plaintext before its
+    // original permissions are put back.
+    #[cfg(unix)]
+    options.mode(0o600);
+    let result = options.open(&tmp).and_then(|file| {
+        let mut output = BufWriter::new(file);
+        write(&mut output)?;
+        output
+            .into_inner()
+            .map_err(|error| error.into_error())?
+            .sync_all()
+    });
+    match result.and_then(|()| replace(&tmp, path)) {
+        Ok(()) => Ok(()),
+        Err(error) => {
+            let _ = fs::remove_file(&tmp);
+            Err(error)
+        }
+    }
+}
+
+/// Moves `tmp` to `path`, replacing whatever is there. When a plain rename
+/// can't do that, because a directory is involved, the old entry is moved
+/// aside first and only removed once the new one is in place.
+fn replace(tmp: &Path, path: &Path) -> Result<()> {
+    let Ok(existing) = fs::symlink_metadata(path) else {
+        return fs::rename(tmp, path);
+    };
+    if !existing.is_dir() && !tmp.is_dir() {
+        return fs::rename(tmp, path);
+    }
+    let old = temp_path(path);
+    fs::rename(path, &old)?;
+    if let Err(error) = fs::rename(tmp, path) {
+        let _ = fs::rename(&old, path);
+        return Err(error);
+    }
+    if existing.is_dir() {
+        fs::remove_dir_all(old)
+    } else {
+        fs::remove_file(old)
+    }
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{name}.{:08x}.tmp", OsRng.next_u32()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+    use crate::stream::NONCE_PREFIX_LEN;
+
+    /// A directory of its own for one test, removed afterwards.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("filescout-{name}-{}", process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir(&path).unwrap();
+            Scratch(path)
+        }
+
+        /// Encryptor under a key file holding 32 `byte`s.
+        fn encryptor(&self, byte: u8) -> AesEncryptor {
+            let path = self.0.join(format!("key-{byte}"));
+            fs::write(&path, [byte; KEY_LEN]).unwrap();
+            AesEncryptor::new(KeySource::KeyFile(path), Cipher::Aes256Gcm)
+        }
+
+        fn entries(&self) -> Vec<String> {
+            let mut entries: Vec<String> = fs::read_dir(&self.0)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            entries.sort();
+            entries
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// More than two segments, so the last one is partial.
+    fn contents() -> Vec<u8> {
+        (0..SEGMENT_SIZE * 2 + 5).map(|i| i as u8).collect()
+    }
+
+    /// A directory with a nested file, and the same again encrypted.
+    fn encrypted_dir(scratch: &Scratch) -> PathBuf {
+        let dir = scratch.0.join("dir");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a"), contents()).unwrap();
+        fs::write(dir.join("sub").join("b"), b"b").unwrap();
+        let encrypted = scratch.0.join("dir.enc");
+        scratch
+            .encryptor(1)
+            .encrypt_dir(&dir, &encrypted, &())
+            .unwrap();
+        encrypted
+    }
+
+    #[test]
+    fn round_trips_a_file() {
+        let scratch = Scratch::new("file");
+        let (plain, encrypted, output) = (
+            scratch.0.join("plain"),
+            scratch.0.join("plain.enc"),
+            scratch.0.join("output"),
+        );
+        fs::write(&plain, contents()).unwrap();
+        let encryptor = scratch.encryptor(1);
+        encryptor.encrypt_file(&plain, &encrypted, &()).unwrap();
+        assert_ne!(fs::read(&encrypted).unwrap(), contents());
+        encryptor
+            .decrypt_file(&encrypted, &output, false, &())
+            .unwrap();
+        assert_eq!(fs::read(&output).unwrap(), contents());
+    }
+
+    #[test]
+    fn round_trips_a_directory() {
+        let scratch = Scratch::new("dir");
+        let encrypted = encrypted_dir(&scratch);
+        let output = scratch.0.join("output");
+        scratch
+            .encryptor(1)
+            .decrypt_file(&encrypted, &output, false, &())
+            .unwrap();
+        assert_eq!(fs::read(output.join("a")).unwrap(), contents());
+        assert_eq!(fs::read(output.join("sub").join("b")).unwrap(), b"b");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_permissions_and_mtime() {
+        let scratch = Scratch::new("metadata");
+        let (plain, encrypted, output) = (
+            scratch.0.join("plain"),
+            scratch.0.join("plain.enc"),
+            scratch.0.join("output"),
+        );
+        fs::write(&plain, b"plain").unwrap();
+        fs::set_permissions(&plain, fs::Permissions::from_mode(0o4755)).unwrap();
+        let mtime = FileTime::from_unix_time(1_600_000_000, 123_456_789);
+        filetime::set_file_mtime(&plain, mtime).unwrap();
+        let encryptor = scratch.encryptor(1);
+        encryptor.encrypt_file(&plain, &encrypted, &()).unwrap();
+        encryptor
+            .decrypt_file(&encrypted, &output, false, &())
+            .unwrap();
+        let metadata = fs::metadata(&output).unwrap();
+        // The setuid bit is dropped.
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o755);
+        assert_eq!(FileTime::from_last_modification_time(&metadata), mtime);
+    }
+
+    #[test]
+    fn wrong_key_leaves_no_output() {
+        let scratch = Scratch::new("wrong-key");
+        let plain = scratch.0.join("plain");
+        fs::write(&plain, contents()).unwrap();
+        let file = scratch.0.join("plain.enc");
+        scratch
+            .encryptor(1)
+            .encrypt_file(&plain, &file, &())
+            .unwrap();
+        let dir = encrypted_dir(&scratch);
+        let before = scratch.entries();
+        for encrypted in [file, dir] {
+            let output = scratch.0.join("output");
+            let error = scratch
+                .encryptor(2)
+                .decrypt_file(&encrypted, &output, false, &())
+                .unwrap_err();
+            assert!(is_wrong_key(&error));
+            assert!(!output.exists());
+            // Nor is anything left behind half written.
+            let mut after = scratch.entries();
+            after.retain(|name| name != "key-2");
+            assert_eq!(after, before);
+        }
+    }
+
+    #[test]
+    fn truncated_input_keeps_existing_output() {
+        let scratch = Scratch::new("truncated");
+        let plain = scratch.0.join("plain");
+        fs::write(&plain, contents()).unwrap();
+        let file = scratch.0.join("plain.enc");
+        let encryptor = scratch.encryptor(1);
+        encryptor.encrypt_file(&plain, &file, &()).unwrap();
+        let dir = encrypted_dir(&scratch);
+        for encrypted in [file, dir] {
+            let mut bytes = fs::read(&encrypted).unwrap();
+            bytes.truncate(bytes.len() - 1);
+            fs::write(&encrypted, bytes).unwrap();
+
+            let output = scratch.0.join("output");
+            encryptor
+                .decrypt_file(&encrypted, &output, false, &())
+                .unwrap_err();
+            assert!(!output.exists());
+
+            fs::write(&output, b"old").unwrap();
+            let before = scratch.entries();
+            encryptor
+                .decrypt_file(&encrypted, &output, true, &())
+                .unwrap_err();
+            assert_eq!(fs::read(&output).unwrap(), b"old");
+            assert_eq!(scratch.entries(), before);
+            fs::remove_file(&output).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_bad_nonce_length() {
+        let path = env::temp_dir().join(format!("filescout-nonce-{}", process::id()));
+        let kdf = Kdf::Raw { salt: vec![0; 16] };
+        let header = Header {
+            cipher: Cipher::Aes256Gcm,
+            nonce: vec![0; NONCE_PREFIX_LEN + 1],
+            ..Header::example(kdf)
+        };
+        let mut bytes = header.to_bytes();
+        bytes.extend_from_slice(&[0; TAG_LEN]);
+        fs::write(&path, bytes).unwrap();
+        let opened = AesEncryptor::open_with(&path, &(), |_| Ok(Zeroizing::new([0; KEY_LEN])));
+        fs::remove_file(&path).unwrap();
+        let error = opened.err().unwrap();
+        assert!(matches!(
+            CryptoError::of(&error),
+            Some(CryptoError::Malformed(_))
+        ));
+    }
 
-        let mut file = File::create(output)?;
-        file.write_all(&nonce)?;
-        file.write_all(&ciphertext)?;
-        Ok(())
+    #[test]
+    fn rejects_excessive_argon2_costs() {
+        let encryptor =
+            AesEncryptor::new(KeySource::passphrase(String::from("pw")), Cipher::Aes256Gcm);
+        for (m_cost, t_cost, p_cost) in
+            [(u32::MAX, 2, 1), (19456, u32::MAX, 1), (19456, 2, u32::MAX)]
+        {
+            let kdf = Kdf::Argon2id {
+                m_cost,
+                t_cost,
+                p_cost,
+                salt: vec![0; SALT_LEN],
+            };
+            let error = encryptor.file_key(&Header::example(kdf)).err().unwrap();
+            assert!(matches!(
+                CryptoError::of(&error),
+                Some(CryptoError::Malformed(_))
+            ));
+        }
     }
 }
@@ -1,11 +1,22 @@
+use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
 use ratatui::widgets::ListState;
 #[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
 use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap},
     fs::{self, File},
-    io::{Error, ErrorKind, Result},
+    io::{Error, ErrorKind, Read, Result},
     path::{Path, PathBuf},
 };
+use zeroize::Zeroize;
+
+use crate::{
+    crypto_handler::{detect_format, Format},
+    filter::Filter,
+    jobs::{Progress, Tracked},
+    sort::Sort,
+};
 
 #[derive(Default)]
 pub struct FileStruct {
@@ -21,39 +32,59 @@ pub struct FileStruct {
     pub next_dir: Vec<PathBuf>,
     pub error: Option<Error>,
     pub content: String,
+    /// Format of the selected file when it is encrypted. Its ciphertext
+    /// isn't shown; `content` only holds a preview once one was asked for.
+    pub encrypted: Option<Format>,
+    /// Real names of encrypted files that hide them, learnt as they are
+    /// unlocked.
+    pub real_names: HashMap<PathBuf, String>,
+    /// Entries of the current directory marked for a batch operation.
+    pub marked: BTreeSet<PathBuf>,
+    /// Sort orders chosen for particular directories this session.
+    pub sorts: HashMap<PathBuf, Sort>,
+    /// Sort order of every other directory.
+    pub default_sort: Sort,
+    pub filter: Filter,
+    /// How many entries of the current directory the filter leaves out.
+    pub hidden: usize,
+    /// Set while a fuzzy filter narrows `current_dir`.
+    pub narrowed: Option<Narrowed>,
     pub permission: String,
 }
 
+/// A fuzzy filter over the current directory.
+pub struct Narrowed {
+    pub query: String,
+    /// The whole listing, put back once the filter is cleared.
+    all: Vec<PathBuf>,
+    /// Positions of the characters of each listed label that match.
+    pub positions: HashMap<PathBuf, Vec<usize>>,
+}
+
 trait FileFun {
-    fn get_dirs_and_files(path: &Path) -> Vec<PathBuf>;
+    fn get_dirs_and_files(&self, path: &Path) -> (Vec<PathBuf>, usize);
     fn parent_dir_fn(&mut self);
     #[cfg(unix)]
     fn format_permissions(mode: u32) -> String;
 }
 
 impl FileFun for FileStruct {
-    fn get_dirs_and_files(path: &Path) -> Vec<PathBuf> {
-        let mut dirs = Vec::new();
-        let mut files = Vec::new();
-
-        if let Ok(entries) = fs::read_dir(path) {
-            for entry in entries.filter_map(Result::ok) {
-                let entry_path = entry.path();
-                if entry_path.is_dir() {
-                    dirs.push(entry.path());
-                } else {
-                    files.push(entry.path());
-                }
-            }
-            dirs.append(&mut files);
-        }
-        dirs
+    fn get_dirs_and_files(&self, path: &Path) -> (Vec<PathBuf>, usize) {
+        let (mut entries, hidden) = self.filter.list(path);
+        self.sort_for(path).apply(&mut entries);
+        (entries, hidden)
     }
 
     fn parent_dir_fn(&mut self) {
         let mut files: Vec<PathBuf> = vec![];
         if let Some(parent) = self.pwd.parent() {
-            files = FileStruct::get_dirs_and_files(parent);
+            files = self.get_dirs_and_files(parent).0;
+            // A filtered out directory can still be entered, and has to be
+            // there to go back up from.
+            if !files.contains(&self.pwd) {
+                files.push(self.pwd.to_path_buf());
+                self.sort_for(parent).apply(&mut files);
+            }
         }
         self.parent_dir = files;
     }
@@ -84,50 +115,198 @@ impl FileStruct {
     pub fn present_dir_fn(&mut self, path: &Path, index: Option<usize>) {
         let pwd = fs::canonicalize(path).unwrap();
         self.error = None;
+        if pwd != self.pwd {
+            self.marked.clear();
+        }
+        // Listing the same directory again, as when a job finishes, keeps
+        // the fuzzy filter being typed.
+        let query = self
+            .narrowed
+            .take()
+            .filter(|_| pwd == self.pwd)
+            .map(|narrowed| narrowed.query);
         self.pwd = pwd.to_path_buf();
         if let Some(parent) = self.pwd.parent() {
             self.parent = parent.to_path_buf()
         }
-        let files = FileStruct::get_dirs_and_files(pwd.as_path());
-
-        let index = index.unwrap_or_default();
-        self.current_state.select(Some(index));
-        self.current_path = Some(files[index].to_path_buf());
-        if !files.is_empty() {
-            if files[index].is_dir() {
-                self.next_dir_fn(files[index].as_path());
-            } else if files[index].is_file() {
-                self.read_file(files[index].to_path_buf());
-            } else {
+        let (files, hidden) = self.get_dirs_and_files(pwd.as_path());
+        self.hidden = hidden;
+
+        self.current_dir = files;
+        let index = match query {
+            // `index` was into the narrowed list, so the entry is looked up.
+            Some(query) => {
+                self.filter_by(&query);
+                self.current_path
+                    .as_ref()
+                    .and_then(|path| self.current_dir.iter().position(|entry| entry == path))
+                    .or(Some(0))
+            }
+            None => index,
+        };
+        // The entry at `index` may be gone, or filtered out.
+        let len = self.current_dir.len();
+        let index = index.unwrap_or_default().min(len.saturating_sub(1));
+        self.select((len > 0).then_some(index));
+        self.parent_dir_fn();
+    }
+
+    /// Selects the entry at `index` of the current directory and shows it in
+    /// the next pane.
+    pub fn select(&mut self, index: Option<usize>) {
+        self.current_state.select(index);
+        self.current_path = index.and_then(|index| self.current_dir.get(index)).cloned();
+        match self.current_path.clone() {
+            Some(path) if path.is_dir() => self.next_dir_fn(&path),
+            Some(path) if path.is_file() => self.read_file(path),
+            Some(_path) => {
                 #[cfg(unix)]
-                self.file_permission(files[index].as_path());
+                self.file_permission(&_path);
                 self.next_dir.clear();
             }
+            None => {
+                self.next_dir.clear();
+                self.content.zeroize();
+                self.line_count = 0;
+                self.encrypted = None;
+            }
+        }
+    }
+
+    /// Name an entry is listed under.
+    pub fn label(&self, path: &Path) -> String {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        match self.real_names.get(path) {
+            Some(real_name) => format!("{real_name} ({name})"),
+            None => name.into_owned(),
         }
+    }
 
-        self.current_dir = files;
-        self.parent_dir_fn();
+    /// Lists only the entries whose label fuzzily matches `query`, best
+    /// matches first, and selects the best one.
+    pub fn narrow(&mut self, query: &str) {
+        self.filter_by(query);
+        self.select((!self.current_dir.is_empty()).then_some(0));
+    }
+
+    /// `narrow` without selecting anything.
+    fn filter_by(&mut self, query: &str) {
+        let all = match self.narrowed.take() {
+            Some(narrowed) => narrowed.all,
+            None => std::mem::take(&mut self.current_dir),
+        };
+        let matcher = SkimMatcherV2::default();
+        let mut matches: Vec<(i64, &PathBuf, Vec<usize>)> = all
+            .iter()
+            .filter_map(|path| {
+                let (score, positions) = matcher.fuzzy_indices(&self.label(path), query)?;
+                Some((score, path, positions))
+            })
+            .collect();
+        // The sort is stable, so equally good matches keep the listing order.
+        matches.sort_by_key(|(score, ..)| Reverse(*score));
+        self.current_dir = matches
+            .iter()
+            .map(|(_, path, _)| path.to_path_buf())
+            .collect();
+        let positions = matches
+            .into_iter()
+            .map(|(_, path, positions)| (path.to_path_buf(), positions))
+            .collect();
+        self.narrowed = Some(Narrowed {
+            query: query.to_string(),
+            all,
+            positions,
+        });
+    }
+
+    /// Puts back the whole listing after `narrow`, keeping the selected entry
+    /// selected.
+    pub fn widen(&mut self) {
+        if let Some(narrowed) = self.narrowed.take() {
+            self.current_dir = narrowed.all;
+            match self
+                .current_path
+                .as_ref()
+                .and_then(|path| self.current_dir.iter().position(|entry| entry == path))
+            {
+                Some(index) => self.current_state.select(Some(index)),
+                None => self.select((!self.current_dir.is_empty()).then_some(0)),
+            }
+        }
     }
 
     pub fn next_dir_fn(&mut self, path: &Path) {
         #[cfg(unix)]
         self.file_permission(path);
-        let files = FileStruct::get_dirs_and_files(path);
+        let files = self.get_dirs_and_files(path).0;
         self.next = path.to_path_buf();
         self.next_dir = files;
     }
 
     pub fn read_file(&mut self, path: PathBuf) {
+        let loaded = load_file(&path, &());
+        self.show_file(&path, loaded);
+    }
+
+    /// Shows a file read by `load_file` in the next pane, or why it couldn't
+    /// be read.
+    pub fn show_file(&mut self, path: &Path, loaded: Result<(Option<Format>, String)>) {
         #[cfg(unix)]
-        self.file_permission(path.as_path());
-        let line = fs::read_to_string(path).unwrap_or_else(|error| {
+        self.file_permission(path);
+        // The last file may have been a decrypted preview.
+        self.content.zeroize();
+        let (encrypted, line) = loaded.unwrap_or_else(|error| {
             self.error = Some(error);
-            String::new()
+            (None, String::new())
         });
+        self.encrypted = encrypted;
         self.line_count = line.lines().count();
         self.content = line;
     }
 
+    /// Lists the current directory again, keeping the selected entry
+    /// selected if it is still listed.
+    pub fn reload(&mut self) {
+        let pwd = self.pwd.to_path_buf();
+        let (files, _) = self.get_dirs_and_files(&pwd);
+        let index = self
+            .current_path
+            .as_ref()
+            .and_then(|path| files.iter().position(|entry| entry == path));
+        self.present_dir_fn(&pwd, index);
+    }
+
+    pub fn sort_for(&self, dir: &Path) -> Sort {
+        self.sorts.get(dir).copied().unwrap_or(self.default_sort)
+    }
+
+    /// Sorts the current directory by `sort` from now on, keeping the
+    /// selected entry selected.
+    pub fn set_sort(&mut self, sort: Sort) {
+        self.sorts.insert(self.pwd.to_path_buf(), sort);
+        match &mut self.narrowed {
+            // The narrowed list stays ranked by how well entries match.
+            Some(narrowed) => sort.apply(&mut narrowed.all),
+            None => sort.apply(&mut self.current_dir),
+        }
+        let index = self
+            .current_path
+            .as_ref()
+            .and_then(|path| self.current_dir.iter().position(|entry| entry == path));
+        self.current_state.select(index.or(Some(0)));
+    }
+
+    /// Drops everything shown from decrypted files: hidden names and the
+    /// preview of the selected file.
+    pub fn forget_decrypted(&mut self) {
+        self.real_names.clear();
+        if self.encrypted.is_some() {
+            self.content.zeroize();
+            self.line_count = 0;
+        }
+    }
+
     #[cfg(unix)]
     fn file_permission(&mut self, path: &Path) {
         match fs::metadata(path) {
@@ -198,3 +377,17 @@ impl FileStruct {
         Err(Error::new(ErrorKind::Unsupported, ""))
     }
 }
+
+/// Reads `path` for the next pane, along with its format when it is
+/// encrypted. Nothing of encrypted files is read, as only a preview decrypted
+/// on request is shown.
+pub fn load_file(path: &Path, progress: &impl Progress) -> Result<(Option<Format>, String)> {
+    if let Ok(format) = detect_format(path) {
+        return Ok((Some(format), String::new()));
+    }
+    let file = File::open(path)?;
+    progress.total(file.metadata()?.len());
+    let mut line = String::new();
+    Tracked::new(file, progress).read_to_string(&mut line)?;
+    Ok((None, line))
+}
@@ -0,0 +1,84 @@
+//! Decides which entries of a directory are listed: dotfiles, files matched
+//! by `.gitignore` or `.ignore` rules, and the configured exclusions can
+//! each be left out.
+
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+
+use crate::config::ListingConfig;
+
+#[derive(Default)]
+pub struct Filter {
+    pub show_hidden: bool,
+    /// Leave out what `.gitignore` and `.ignore` files in the directory or
+    /// its parents ignore.
+    pub respect_ignore: bool,
+    /// Names that are never listed.
+    exclude: GlobSet,
+}
+
+impl Filter {
+    pub fn new(config: &ListingConfig) -> Result<Self> {
+        let mut exclude = GlobSetBuilder::new();
+        for pattern in &config.exclude {
+            let glob = Glob::new(pattern).map_err(|error| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Bad exclude pattern: {error}"),
+                )
+            })?;
+            exclude.add(glob);
+        }
+        Ok(Self {
+            show_hidden: config.show_hidden,
+            respect_ignore: config.respect_ignore,
+            exclude: exclude
+                .build()
+                .map_err(|error| Error::new(ErrorKind::InvalidInput, error))?,
+        })
+    }
+
+    /// The entries of `dir` that pass the filter, and how many didn't.
+    pub fn list(&self, dir: &Path) -> (Vec<PathBuf>, usize) {
+        let Ok(total) = fs::read_dir(dir).map(Iterator::count) else {
+            return (Vec::new(), 0);
+        };
+        let entries: Vec<PathBuf> = self
+            .walker(dir)
+            .max_depth(Some(1))
+            .build()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.depth() == 1)
+            .map(|entry| entry.into_path())
+            .collect();
+        let hidden = total.saturating_sub(entries.len());
+        (entries, hidden)
+    }
+
+    /// A walk below `dir` leaving out what the filter does. Excluded
+    /// directories aren't descended into.
+    pub fn walker(&self, dir: &Path) -> WalkBuilder {
+        let exclude = self.exclude.clone();
+        let mut walker = WalkBuilder::new(dir);
+        walker
+            .hidden(!self.show_hidden)
+            .parents(self.respect_ignore)
+            .ignore(self.respect_ignore)
+            .git_ignore(self.respect_ignore)
+            .git_global(self.respect_ignore)
+            .git_exclude(self.respect_ignore)
+            .filter_entry(move |entry| !exclude.is_match(entry.file_name()));
+        walker
+    }
+
+    /// Whether anything can be left out at all.
+    pub fn is_active(&self) -> bool {
+        !self.show_hidden || self.respect_ignore || !self.exclude.is_empty()
+    }
+}
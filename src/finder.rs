@@ -0,0 +1,135 @@
+//! Finds files anywhere below a directory by fuzzy matching their paths.
+//! The tree is walked in the background, so a big tree is searchable while
+//! it is still being walked.
+
+use std::{
+    cmp::Reverse,
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+};
+
+use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
+use ratatui::widgets::ListState;
+use tokio::sync::mpsc::Sender;
+
+use crate::{filter::Filter, ui::Message, walk::Walk};
+
+/// Most matches kept and listed, so a vague query on a huge tree stays fast
+/// to draw.
+const MAX_RESULTS: usize = 500;
+
+pub struct Finder {
+    walk: Walk,
+    pub root: PathBuf,
+    pub query: String,
+    pub state: ListState,
+    /// Every path found so far, with the text it is matched on.
+    candidates: Vec<(PathBuf, String)>,
+    /// The best matches, best first.
+    results: Vec<Match>,
+    /// Whether the walk is still going.
+    pub scanning: bool,
+    matcher: SkimMatcherV2,
+}
+
+struct Match {
+    index: usize,
+    score: i64,
+    positions: Vec<usize>,
+}
+
+impl Finder {
+    /// Starts walking `root`, leaving out what `filter` does. Batches of
+    /// paths are posted as `Message::Found`.
+    pub fn start(root: PathBuf, filter: &Filter, tx: Sender<Message>) -> Self {
+        let walk = Walk::start(
+            filter.walker(&root),
+            tx,
+            |entry, paths: &mut Vec<PathBuf>, _| {
+                if entry.depth() > 0 {
+                    paths.push(entry.into_path());
+                }
+                ControlFlow::Continue(())
+            },
+            |id, paths, done| Message::Found { id, paths, done },
+        );
+        Self {
+            walk,
+            root,
+            query: String::new(),
+            state: ListState::default().with_selected(Some(0)),
+            candidates: Vec::new(),
+            results: Vec::new(),
+            scanning: true,
+            matcher: SkimMatcherV2::default(),
+        }
+    }
+
+    /// Tells the walk's batches apart from those of an earlier finder.
+    pub fn id(&self) -> usize {
+        self.walk.id
+    }
+
+    /// Takes in a batch from the walk, ranking it against the query.
+    pub fn add(&mut self, paths: Vec<PathBuf>, done: bool) {
+        let start = self.candidates.len();
+        self.candidates.extend(paths.into_iter().map(|path| {
+            let text = path
+                .strip_prefix(&self.root)
+                .unwrap_or(&path)
+                .to_string_lossy()
+                .into_owned();
+            (path, text)
+        }));
+        self.rank(start);
+        self.scanning = !done;
+    }
+
+    pub fn set_query(&mut self, query: String) {
+        self.query = query;
+        self.results.clear();
+        self.rank(0);
+        self.state.select(Some(0));
+    }
+
+    /// Matches the candidates from `start` on and merges them into the
+    /// results.
+    fn rank(&mut self, start: usize) {
+        for (index, (_, text)) in self.candidates.iter().enumerate().skip(start) {
+            if let Some((score, positions)) = self.matcher.fuzzy_indices(text, &self.query) {
+                self.results.push(Match {
+                    index,
+                    score,
+                    positions,
+                });
+            }
+        }
+        // Stable, so equally good matches stay in the order they were found.
+        self.results.sort_by_key(|result| Reverse(result.score));
+        self.results.truncate(MAX_RESULTS);
+    }
+
+    /// The listed matches: each path relative to the root, and the positions
+    /// of its characters that match.
+    pub fn results(&self) -> impl Iterator<Item = (&str, &[usize])> {
+        self.results.iter().map(|result| {
+            (
+                self.candidates[result.index].1.as_str(),
+                result.positions.as_slice(),
+            )
+        })
+    }
+
+    pub fn result_count(&self) -> usize {
+        self.results.len()
+    }
+
+    pub fn found(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn selected(&self) -> Option<&Path> {
+        let result = self.results.get(self.state.selected()?)?;
+        Some(&self.candidates[result.index].0)
+    }
+}
@@ -0,0 +1,484 @@
+//! Header written at the start of every `.enc` file.
+//!
+//! All integers are little-endian:
+//!
+//! ```text
+//! magic      5 bytes   b"FSENC"
+//! version    u8        FORMAT_VERSION
+//! cipher     u8        1 = AES-256-GCM, 2 = ChaCha20-Poly1305,
+//!                      3 = XChaCha20-Poly1305
+//! kdf        u8        1 = Argon2id over a passphrase,
+//!                      2 = HKDF-SHA256 over a raw 256-bit key,
+//!                      3 = HKDF-SHA256 over a random data key wrapped for
+//!                          X25519 recipients (see `recipient.rs`)
+//!   argon2id: m_cost u32, t_cost u32, p_cost u32, salt_len u8, salt
+//!   raw:      salt_len u8, salt
+//!   x25519:   salt_len u8, salt, count u16, then per recipient:
+//!             ephemeral public key 32 bytes, key_len u8, wrapped key
+//! nonce_len  u8, nonce prefix for the STREAM segments (19 bytes for
+//!                      XChaCha20-Poly1305, 7 for the others)
+//! segment    u32       plaintext bytes per segment
+//! name_len   u16, original file name (UTF-8)
+//! size       u64       original file size in bytes (for an archive, the
+//!                      total size of the files packed into it)
+//! ext_count  u16, then per record: tag u8, len u16, data
+//! ```
+//!
+//! The extension records let later versions add optional fields without
+//! breaking older readers, which skip tags they don't know. Known tags:
+//!
+//! ```text
+//! 1  EXT_ARCHIVE   (empty) contents are a tar archive of a directory
+//! 2  EXT_SEALED_NAME  nonce 12 bytes, then the original name encrypted
+//!                  with ChaCha20-Poly1305 under a key derived from the
+//!                  file key; `name` is left empty
+//! 3  EXT_METADATA  mode u32 (permission bits), then modification and
+//!                  access times, each as seconds i64 and nanoseconds u32
+//! ```
+//!
+//! The whole header is passed to the cipher as associated data, so none of
+//! it can be altered without decryption failing.
+//!
+//! The encrypted segments described in `stream.rs` follow the header.
+//! Version 1 sealed the whole file in one piece and was never released.
+
+use std::io::{ErrorKind, Read, Result, Write};
+
+use filetime::FileTime;
+use serde::Deserialize;
+
+use crate::{
+    crypto_handler::CryptoError,
+    stream::{NONCE_PREFIX_LEN, XNONCE_PREFIX_LEN},
+};
+
+pub const MAGIC: &[u8; 5] = b"FSENC";
+pub const FORMAT_VERSION: u8 = 2;
+
+pub const EXT_ARCHIVE: u8 = 1;
+pub const EXT_SEALED_NAME: u8 = 2;
+pub const EXT_METADATA: u8 = 3;
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq)]
+pub enum Cipher {
+    #[default]
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    /// Faster than AES on machines without AES instructions.
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+    /// ChaCha20-Poly1305 with a 192-bit nonce, leaving a much longer random
+    /// nonce prefix.
+    #[serde(rename = "xchacha20-poly1305")]
+    XChaCha20Poly1305,
+}
+
+impl Cipher {
+    fn id(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 1,
+            Cipher::ChaCha20Poly1305 => 2,
+            Cipher::XChaCha20Poly1305 => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self> {
+        match id {
+            1 => Ok(Cipher::Aes256Gcm),
+            2 => Ok(Cipher::ChaCha20Poly1305),
+            3 => Ok(Cipher::XChaCha20Poly1305),
+            id => Err(CryptoError::Malformed(format!("unknown cipher id {id}")).into()),
+        }
+    }
+
+    pub fn nonce_prefix_len(self) -> usize {
+        match self {
+            Cipher::Aes256Gcm | Cipher::ChaCha20Poly1305 => NONCE_PREFIX_LEN,
+            Cipher::XChaCha20Poly1305 => XNONCE_PREFIX_LEN,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Kdf {
+    Argon2id {
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+        salt: Vec<u8>,
+    },
+    Raw {
+        salt: Vec<u8>,
+    },
+    X25519 {
+        salt: Vec<u8>,
+        recipients: Vec<WrappedKey>,
+    },
+}
+
+impl Kdf {
+    /// What the user needs to open a file using this derivation.
+    pub fn key_name(&self) -> &'static str {
+        match self {
+            Kdf::Argon2id { .. } => "a passphrase",
+            Kdf::Raw { .. } => "a key file or key variable",
+            Kdf::X25519 { .. } => "an identity file",
+        }
+    }
+}
+
+/// A file's data key sealed for one recipient.
+#[derive(Clone)]
+pub struct WrappedKey {
+    pub ephemeral: [u8; 32],
+    pub key: Vec<u8>,
+}
+
+#[derive(Clone)]
+pub struct Header {
+    pub cipher: Cipher,
+    pub kdf: Kdf,
+    pub nonce: Vec<u8>,
+    pub segment_size: u32,
+    pub name: String,
+    pub size: u64,
+    pub extensions: Vec<(u8, Vec<u8>)>,
+}
+
+impl Header {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.push(FORMAT_VERSION);
+        buf.push(self.cipher.id());
+        match &self.kdf {
+            Kdf::Argon2id {
+                m_cost,
+                t_cost,
+                p_cost,
+                salt,
+            } => {
+                buf.push(1);
+                buf.extend_from_slice(&m_cost.to_le_bytes());
+                buf.extend_from_slice(&t_cost.to_le_bytes());
+                buf.extend_from_slice(&p_cost.to_le_bytes());
+                buf.push(salt.len() as u8);
+                buf.extend_from_slice(salt);
+            }
+            Kdf::Raw { salt } => {
+                buf.push(2);
+                buf.push(salt.len() as u8);
+                buf.extend_from_slice(salt);
+            }
+            Kdf::X25519 { salt, recipients } => {
+                buf.push(3);
+                buf.push(salt.len() as u8);
+                buf.extend_from_slice(salt);
+                buf.extend_from_slice(&(recipients.len() as u16).to_le_bytes());
+                for recipient in recipients {
+                    buf.extend_from_slice(&recipient.ephemeral);
+                    buf.push(recipient.key.len() as u8);
+                    buf.extend_from_slice(&recipient.key);
+                }
+            }
+        }
+        buf.push(self.nonce.len() as u8);
+        buf.extend_from_slice(&self.nonce);
+        buf.extend_from_slice(&self.segment_size.to_le_bytes());
+        buf.extend_from_slice(&(self.name.len() as u16).to_le_bytes());
+        buf.extend_from_slice(self.name.as_bytes());
+        buf.extend_from_slice(&self.size.to_le_bytes());
+        buf.extend_from_slice(&(self.extensions.len() as u16).to_le_bytes());
+        for (tag, data) in &self.extensions {
+            buf.push(*tag);
+            buf.extend_from_slice(&(data.len() as u16).to_le_bytes());
+            buf.extend_from_slice(data);
+        }
+        buf
+    }
+
+    pub fn extension(&self, tag: u8) -> Option<&[u8]> {
+        self.extensions
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, data)| data.as_slice())
+    }
+
+    pub fn is_archive(&self) -> bool {
+        self.extension(EXT_ARCHIVE).is_some()
+    }
+
+    pub fn set_extension(&mut self, tag: u8, data: Vec<u8>) {
+        match self.extensions.iter_mut().find(|(t, _)| *t == tag) {
+            Some((_, old)) => *old = data,
+            None => self.extensions.push((tag, data)),
+        }
+    }
+
+    pub fn metadata(&self) -> Result<Option<Metadata>> {
+        self.extension(EXT_METADATA)
+            .map(Metadata::from_bytes)
+            .transpose()
+    }
+
+    /// Whether the original name is hidden inside the file.
+    pub fn has_sealed_name(&self) -> bool {
+        self.extension(EXT_SEALED_NAME).is_some()
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> Result<()> {
+        writer.write_all(&self.to_bytes())
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> Result<Self> {
+        let mut magic = [0u8; 5];
+        reader
+            .read_exact(&mut magic)
+            .map_err(|error| match error.kind() {
+                ErrorKind::UnexpectedEof => CryptoError::NotEncrypted.into(),
+                _ => error,
+            })?;
+        if &magic != MAGIC {
+            return Err(CryptoError::NotEncrypted.into());
+        }
+        let version = read_u8(reader)?;
+        if version != FORMAT_VERSION {
+            return Err(CryptoError::UnsupportedVersion(version).into());
+        }
+        let cipher = Cipher::from_id(read_u8(reader)?)?;
+        let kdf = match read_u8(reader)? {
+            1 => {
+                let m_cost = read_u32(reader)?;
+                let t_cost = read_u32(reader)?;
+                let p_cost = read_u32(reader)?;
+                let len = read_u8(reader)? as usize;
+                Kdf::Argon2id {
+                    m_cost,
+                    t_cost,
+                    p_cost,
+                    salt: read_vec(reader, len)?,
+                }
+            }
+            2 => {
+                let len = read_u8(reader)? as usize;
+                Kdf::Raw {
+                    salt: read_vec(reader, len)?,
+                }
+            }
+            3 => {
+                let len = read_u8(reader)? as usize;
+                let salt = read_vec(reader, len)?;
+                let mut recipients = Vec::new();
+                for _ in 0..read_u16(reader)? {
+                    let mut ephemeral = [0u8; 32];
+                    read_exact(reader, &mut ephemeral)?;
+                    let len = read_u8(reader)? as usize;
+                    recipients.push(WrappedKey {
+                        ephemeral,
+                        key: read_vec(reader, len)?,
+                    });
+                }
+                Kdf::X25519 { salt, recipients }
+            }
+            id => {
+                return Err(
+                    CryptoError::Malformed(format!("unknown key derivation id {id}")).into(),
+                )
+            }
+        };
+        let len = read_u8(reader)? as usize;
+        let nonce = read_vec(reader, len)?;
+        let segment_size = read_u32(reader)?;
+        let len = read_u16(reader)? as usize;
+        let name = String::from_utf8(read_vec(reader, len)?)
+            .map_err(|_| CryptoError::Malformed(String::from("file name is not UTF-8")))?;
+        let size = read_u64(reader)?;
+
+        let mut extensions = Vec::new();
+        for _ in 0..read_u16(reader)? {
+            let tag = read_u8(reader)?;
+            let len = read_u16(reader)? as usize;
+            extensions.push((tag, read_vec(reader, len)?));
+        }
+
+        Ok(Self {
+            cipher,
+            kdf,
+            nonce,
+            segment_size,
+            name,
+            size,
+            extensions,
+        })
+    }
+}
+
+/// Permission bits and timestamps of the original file or directory, put
+/// back when it is decrypted.
+#[derive(Clone, Copy)]
+pub struct Metadata {
+    pub mode: u32,
+    pub modified: FileTime,
+    pub accessed: FileTime,
+}
+
+impl Metadata {
+    const LEN: usize = 28;
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::LEN);
+        buf.extend_from_slice(&self.mode.to_le_bytes());
+        for time in [self.modified, self.accessed] {
+            buf.extend_from_slice(&time.unix_seconds().to_le_bytes());
+            buf.extend_from_slice(&time.nanoseconds().to_le_bytes());
+        }
+        buf
+    }
+
+    fn from_bytes(mut data: &[u8]) -> Result<Self> {
+        if data.len() != Self::LEN {
+            return Err(CryptoError::Malformed(String::from("bad metadata")).into());
+        }
+        let reader = &mut data;
+        let mode = read_u32(reader)?;
+        let mut time = || -> Result<FileTime> {
+            let seconds = read_u64(reader)? as i64;
+            Ok(FileTime::from_unix_time(seconds, read_u32(reader)?))
+        };
+        let modified = time()?;
+        let accessed = time()?;
+        Ok(Self {
+            mode,
+            modified,
+            accessed,
+        })
+    }
+}
+
+/// `read_exact` that reports running out of input as a truncated file.
+fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).map_err(|error| match error.kind() {
+        ErrorKind::UnexpectedEof => CryptoError::Truncated.into(),
+        _ => error,
+    })
+}
+
+fn read_vec(reader: &mut impl Read, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    read_exact(reader, &mut buf)?;
+    Ok(buf)
+}
+
+fn read_u8(reader: &mut impl Read) -> Result<u8> {
+    let mut buf = [0u8; 1];
+    read_exact(reader, &mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u16(reader: &mut impl Read) -> Result<u16> {
+    let mut buf = [0u8; 2];
+    read_exact(reader, &mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    read_exact(reader, &mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    read_exact(reader, &mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+impl Header {
+    /// A header using `kdf`, with an unknown extension, for tests.
+    pub(crate) fn example(kdf: Kdf) -> Self {
+        Header {
+            cipher: Cipher::XChaCha20Poly1305,
+            kdf,
+            nonce: vec![7; XNONCE_PREFIX_LEN],
+            segment_size: 64 * 1024,
+            name: String::from("notes.txt"),
+            size: 1234,
+            extensions: vec![(EXT_ARCHIVE, Vec::new()), (200, vec![1, 2, 3])],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let kdfs = [
+            Kdf::Argon2id {
+                m_cost: 19456,
+                t_cost: 2,
+                p_cost: 1,
+                salt: vec![1; 16],
+            },
+            Kdf::Raw { salt: vec![2; 16] },
+            Kdf::X25519 {
+                salt: vec![3; 16],
+                recipients: vec![WrappedKey {
+                    ephemeral: [4; 32],
+                    key: vec![5; 48],
+                }],
+            },
+        ];
+        for kdf in kdfs {
+            let bytes = Header::example(kdf).to_bytes();
+            let read = Header::read_from(&mut bytes.as_slice()).unwrap();
+            assert_eq!(read.to_bytes(), bytes);
+            assert_eq!(read.name, "notes.txt");
+            assert_eq!(read.size, 1234);
+            assert!(read.is_archive());
+            // Unknown tags are kept, so they survive a rewrite.
+            assert_eq!(read.extension(200), Some([1, 2, 3].as_slice()));
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_version() {
+        let mut bytes = Header::example(Kdf::Raw { salt: vec![0; 16] }).to_bytes();
+        bytes[MAGIC.len()] = FORMAT_VERSION + 1;
+        let error = Header::read_from(&mut bytes.as_slice()).err().unwrap();
+        assert!(matches!(
+            CryptoError::of(&error).unwrap(),
+            CryptoError::UnsupportedVersion(version) if *version == FORMAT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_ids() {
+        let bytes = Header::example(Kdf::Raw { salt: vec![0; 16] }).to_bytes();
+        // Cipher, then key derivation, follow the version.
+        for at in [MAGIC.len() + 1, MAGIC.len() + 2] {
+            let mut bytes = bytes.clone();
+            bytes[at] = 99;
+            let error = Header::read_from(&mut bytes.as_slice()).err().unwrap();
+            assert!(matches!(
+                CryptoError::of(&error).unwrap(),
+                CryptoError::Malformed(_)
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        let bytes = Header::example(Kdf::Raw { salt: vec![0; 16] }).to_bytes();
+        for len in 0..bytes.len() {
+            let error = Header::read_from(&mut &bytes[..len]).err().unwrap();
+            match CryptoError::of(&error).unwrap() {
+                CryptoError::NotEncrypted => assert!(len < MAGIC.len()),
+                CryptoError::Truncated => assert!(len >= MAGIC.len()),
+                _ => panic!("unexpected error for {len} bytes"),
+            }
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use std::{
+    cell::{Cell, RefCell},
+    fmt::{self, Display, Formatter},
+    io::{Error, Read, Result, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc::Sender;
+
+use crate::ui::Message;
+
+/// How often a running job posts progress, so big files don't flood the UI.
+const REPORT_INTERVAL: Duration = Duration::from_millis(100);
+/// Most worker threads a batch runs on, so a big selection doesn't saturate
+/// the disk.
+const MAX_WORKERS: usize = 4;
+
+/// Error a cancelled job's I/O fails with.
+#[derive(Debug)]
+struct Cancelled;
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Hooks long-running work calls as it goes. The defaults do nothing, so `()`
+/// can be passed where nobody is watching.
+pub trait Progress {
+    /// Starts a new phase of work that will process `bytes` in total.
+    fn total(&self, _bytes: u64) {}
+
+    /// Records `bytes` more processed. Returns an error once the job has been
+    /// cancelled, which the caller should propagate to stop the work.
+    fn advance(&self, _bytes: u64) -> Result<()> {
+        Ok(())
+    }
+
+    fn entry(&self, _path: &Path) {}
+}
+
+impl Progress for () {}
+
+pub fn is_cancelled(error: &Error) -> bool {
+    error.get_ref().is_some_and(|inner| inner.is::<Cancelled>())
+}
+
+pub enum JobEvent {
+    Progress {
+        id: usize,
+        done: u64,
+        total: u64,
+        current: Option<PathBuf>,
+    },
+    Finished {
+        id: usize,
+        message: String,
+    },
+}
+
+pub struct Job {
+    pub id: usize,
+    pub label: String,
+    pub done: u64,
+    pub total: u64,
+    pub current: Option<PathBuf>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl Job {
+    pub fn ratio(&self) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            (self.done as f64 / self.total as f64).min(1.0)
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+}
+
+/// Jobs running in the background, kept by the UI thread and updated from
+/// the `JobEvent`s their threads post.
+#[derive(Default)]
+pub struct JobManager {
+    pub jobs: Vec<Job>,
+    next_id: usize,
+}
+
+impl JobManager {
+    pub fn start(&mut self, label: String, tx: Sender<Message>) -> JobHandle {
+        let id = self.next_id;
+        self.next_id += 1;
+        let cancel = Arc::new(AtomicBool::new(false));
+        self.jobs.push(Job {
+            id,
+            label,
+            done: 0,
+            total: 0,
+            current: None,
+            cancel: Arc::clone(&cancel),
+        });
+        JobHandle {
+            id,
+            tx,
+            cancel,
+            done: Cell::new(0),
+            total: Cell::new(0),
+            current: RefCell::new(None),
+            last_report: Cell::new(Instant::now()),
+        }
+    }
+
+    /// Applies `event`, returning the job's final message once it is done.
+    pub fn apply(&mut self, event: JobEvent) -> Option<String> {
+        match event {
+            JobEvent::Progress {
+                id,
+                done,
+                total,
+                current,
+            } => {
+                if let Some(job) = self.jobs.iter_mut().find(|job| job.id == id) {
+                    job.done = done;
+                    job.total = total;
+                    job.current = current;
+                }
+                None
+            }
+            JobEvent::Finished { id, message } => {
+                self.jobs.retain(|job| job.id != id);
+                Some(message)
+            }
+        }
+    }
+
+    /// Cancels the most recently started job that is still running. Returns
+    /// whether there was one.
+    pub fn cancel_latest(&mut self) -> bool {
+        match self.jobs.iter().rev().find(|job| !job.is_cancelled()) {
+            Some(job) => {
+                job.cancel.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The worker thread's side of a job.
+pub struct JobHandle {
+    id: usize,
+    tx: Sender<Message>,
+    cancel: Arc<AtomicBool>,
+    done: Cell<u64>,
+    total: Cell<u64>,
+    current: RefCell<Option<PathBuf>>,
+    last_report: Cell<Instant>,
+}
+
+impl JobHandle {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+
+    fn report(&self) {
+        if self.last_report.get().elapsed() < REPORT_INTERVAL {
+            return;
+        }
+        self.last_report.set(Instant::now());
+        // Dropping an update is fine, the next one carries the same totals.
+        let _ = self.tx.try_send(Message::Job(JobEvent::Progress {
+            id: self.id,
+            done: self.done.get(),
+            total: self.total.get(),
+            current: self.current.borrow().clone(),
+        }));
+    }
+
+    /// Posts the job's outcome. Must be called without holding the
+    /// `FileStruct` lock, since the UI needs it to drain the channel.
+    pub fn finish(self, message: String) -> bool {
+        self.tx
+            .blocking_send(Message::Job(JobEvent::Finished {
+                id: self.id,
+                message,
+            }))
+            .is_ok()
+    }
+}
+
+impl Progress for JobHandle {
+    fn total(&self, bytes: u64) {
+        self.total.set(bytes);
+        self.done.set(0);
+    }
+
+    fn advance(&self, bytes: u64) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Error::other(Cancelled));
+        }
+        self.done.set(self.done.get() + bytes);
+        self.report();
+        Ok(())
+    }
+
+    fn entry(&self, path: &Path) {
+        *self.current.borrow_mut() = Some(path.to_path_buf());
+        self.report();
+    }
+}
+
+/// One job shared by the workers of a batch. Its progress counts finished
+/// items, since the byte counts of files worked on at once would clobber
+/// each other.
+pub struct BatchProgress {
+    job: Mutex<JobHandle>,
+}
+
+impl BatchProgress {
+    pub fn new(job: JobHandle, items: u64) -> Self {
+        job.total(items);
+        Self {
+            job: Mutex::new(job),
+        }
+    }
+
+    pub fn item_done(&self) {
+        // A cancelled batch still counts the items that finished.
+        let _ = self.job.lock().unwrap().advance(1);
+    }
+
+    pub fn into_inner(self) -> JobHandle {
+        self.job.into_inner().unwrap()
+    }
+}
+
+impl Progress for BatchProgress {
+    fn advance(&self, _bytes: u64) -> Result<()> {
+        if self.job.lock().unwrap().is_cancelled() {
+            return Err(Error::other(Cancelled));
+        }
+        Ok(())
+    }
+
+    fn entry(&self, path: &Path) {
+        self.job.lock().unwrap().entry(path)
+    }
+}
+
+/// Runs `work` on every item on a few worker threads, returning the
+/// results in the order of `items`.
+pub fn run_pool<T: Sync, R: Send>(items: &[T], work: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let workers = thread::available_parallelism()
+        .map_or(1, |count| count.get())
+        .min(MAX_WORKERS)
+        .min(items.len());
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(items.iter().map(|_| None).collect::<Vec<_>>());
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                let mut index = next.fetch_add(1, Ordering::Relaxed);
+                while let Some(item) = items.get(index) {
+                    let result = work(item);
+                    results.lock().unwrap()[index] = Some(result);
+                    index = next.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// Reader or writer that reports every byte passing through it to a
+/// `Progress`, and stops with an error once the job is cancelled.
+pub struct Tracked<'a, T, P: Progress> {
+    inner: T,
+    progress: &'a P,
+}
+
+impl<'a, T, P: Progress> Tracked<'a, T, P> {
+    pub fn new(inner: T, progress: &'a P) -> Self {
+        Self { inner, progress }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Read, P: Progress> Read for Tracked<'_, T, P> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.progress.advance(len as u64)?;
+        Ok(len)
+    }
+}
+
+impl<T: Write, P: Progress> Write for Tracked<'_, T, P> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.progress.advance(len as u64)?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,75 @@
+//! The passphrase remembered for the session, so it is typed once rather
+//! than for every file. Each file derives its own key from the passphrase
+//! and a random salt, so it is the passphrase that is kept, never a key.
+
+use std::time::{Duration, Instant};
+
+use zeroize::Zeroizing;
+
+pub struct KeyCache {
+    /// Wiped when the cache locks or is dropped.
+    passphrase: Option<Zeroizing<String>>,
+    /// How long the cache stays unlocked without activity, or `None` to stay
+    /// unlocked until FileScout quits.
+    timeout: Option<Duration>,
+    last_active: Instant,
+    /// How many times the cache has locked, so that jobs started before a
+    /// lock can't unlock it again when they finish.
+    generation: u64,
+}
+
+impl KeyCache {
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            passphrase: None,
+            timeout,
+            last_active: Instant::now(),
+            generation: 0,
+        }
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.passphrase.is_some()
+    }
+
+    pub fn unlock(&mut self, passphrase: Zeroizing<String>) {
+        self.passphrase = Some(passphrase);
+        self.touch();
+    }
+
+    pub fn lock(&mut self) {
+        self.passphrase = None;
+        self.generation += 1;
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// The cached passphrase, if unlocked.
+    pub fn passphrase(&self) -> Option<Zeroizing<String>> {
+        self.passphrase.clone()
+    }
+
+    /// Records activity, restarting the idle timeout.
+    pub fn touch(&mut self) {
+        self.last_active = Instant::now();
+    }
+
+    /// Time left before the cache locks itself, or `None` when it is locked
+    /// already or never times out.
+    pub fn remaining(&self) -> Option<Duration> {
+        let timeout = self.timeout.filter(|_| self.is_unlocked())?;
+        Some(timeout.saturating_sub(self.last_active.elapsed()))
+    }
+
+    /// Locks the cache once it has been idle for the whole timeout. Returns
+    /// whether it did.
+    pub fn expire(&mut self) -> bool {
+        if self.remaining() == Some(Duration::ZERO) {
+            self.lock();
+            return true;
+        }
+        false
+    }
+}
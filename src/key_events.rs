@@ -1,30 +1,53 @@
 use std::{
-    io::{Error, ErrorKind},
-    sync::{Arc, MutexGuard},
+    ffi::{OsStr, OsString},
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
     thread,
 };
 
 use crate::{
-    constant::COLORS,
-    crypto_handler::AesEncryptor,
-    explorer::FileStruct,
-    ui::{FileScout, ViewMode},
+    age_file::{needs_passphrase, AgeEncryptor},
+    config::EncryptionConfig,
+    constant::{COLORS, PREVIEW_LIMIT},
+    crypto_handler::{
+        detect_format, encrypted_files, is_wrong_key, random_name, read_header, AesEncryptor,
+        CryptoError, EncryptedDocument, Format,
+    },
+    explorer::{load_file, FileStruct},
+    finder::Finder,
+    header::Kdf,
+    jobs::{is_cancelled, run_pool, BatchProgress, JobHandle, Progress},
+    key_cache::KeyCache,
+    key_source::KeySource,
+    search::{Search, CONTEXT},
+    ui::{CryptoAction, FileScout, Message, ViewMode},
 };
 use crossterm::event::{KeyCode, KeyModifiers};
 use tokio::sync::mpsc::Sender;
+use zeroize::{Zeroize, Zeroizing};
 
 pub fn handle_events(
     file: &mut FileScout,
     code: KeyCode,
-    tx: Sender<String>,
+    tx: Sender<Message>,
     modifier: KeyModifiers,
 ) {
     let file_clone = Arc::clone(&file.files);
     let mut file_struct = file_clone.lock().unwrap();
+    file.status = None;
 
     match file.mode {
         ViewMode::FileEdit => handle_file_edit(code, file, file_struct, modifier),
         ViewMode::Create | ViewMode::Rename => handle_file_name(code, file, file_struct),
+        ViewMode::Filter => handle_filter(code, file, file_struct),
+        ViewMode::Finder => handle_finder(code, file, file_struct),
+        ViewMode::Search => handle_search(code, file, file_struct, tx),
+        ViewMode::Passphrase => handle_passphrase(code, file, file_struct, tx),
+        ViewMode::Confirm => handle_confirm(code, file, file_struct, tx),
+        ViewMode::SaveAs => handle_save_as(code, file, file_struct, tx),
+        ViewMode::Report => handle_report(code, file),
         _ => match code {
             KeyCode::Char('q') | KeyCode::Char('Q') => file.exit = true,
             KeyCode::Char('r') | KeyCode::Char('R') => {
@@ -34,8 +57,23 @@ pub fn handle_events(
                 }
             }
             KeyCode::Char('n') | KeyCode::Char('N') => file.mode = ViewMode::Create,
-            KeyCode::Char('o') | KeyCode::Char('O') => {
-                match file_struct.file_read() {
+            KeyCode::Char('o') | KeyCode::Char('O') => match &file_struct.current_path {
+                Some(path) if file_struct.encrypted.is_some() => {
+                    let path = path.to_path_buf();
+                    match detect_format(&path) {
+                        Ok(Format::FileScout) => {
+                            request_key(file, &mut file_struct, tx, CryptoAction::Edit(path))
+                        }
+                        Ok(_) => {
+                            file_struct.error = Some(Error::new(
+                                ErrorKind::Unsupported,
+                                "Only FileScout files can be edited",
+                            ))
+                        }
+                        Err(error) => file_struct.error = Some(error),
+                    }
+                }
+                _ => match file_struct.file_read() {
                     Ok(content) => {
                         file.input.total_lines = content.lines().count();
                         file.input.total_letter = content.lines().next().unwrap_or_default().len();
@@ -43,114 +81,258 @@ pub fn handle_events(
                         file.mode = ViewMode::FileEdit;
                     }
                     Err(error) => file_struct.error = Some(error),
-                };
-            }
+                },
+            },
             KeyCode::Char('e') | KeyCode::Char('E') => {
+                if !file_struct.marked.is_empty() {
+                    let paths = file_struct.marked.iter().cloned().collect();
+                    request_key(
+                        file,
+                        &mut file_struct,
+                        tx,
+                        CryptoAction::EncryptBatch(paths),
+                    );
+                } else if let Some(index) = file_struct.current_state.selected() {
+                    let path = file_struct.current_dir[index].to_path_buf();
+                    request_key(file, &mut file_struct, tx, CryptoAction::Encrypt(path));
+                }
+            }
+            KeyCode::Char('a') | KeyCode::Char('A') => {
                 if let Some(index) = file_struct.current_state.selected() {
                     let path = file_struct.current_dir[index].to_path_buf();
                     if path.is_file() {
-                        if let Some(file_name) = path.file_name() {
-                            let file_name = file_name.to_str().unwrap();
-                            let file_name = format!("{}.enc", file_name);
-                            let mut pwd = file_struct.pwd.to_path_buf();
-                            pwd.push(file_name);
-                            let message_clone = Arc::clone(&file.files);
-                            thread::spawn(move || {
-                                match AesEncryptor::new().encrypt_file(&path, &pwd) {
-                                    Ok(()) => {
-                                        let mut msg = message_clone.lock().unwrap();
-                                        let pwd = msg.pwd.to_path_buf();
-                                        if let Some(index) = msg.current_state.selected() {
-                                            msg.present_dir_fn(&pwd, Some(index));
-                                        }
-                                        if tx
-                                            .try_send(String::from("File Encryption completed"))
-                                            .is_err()
-                                        {
-                                            msg.error = Some(Error::new(
-                                                ErrorKind::Other,
-                                                "Failed to refresh",
-                                            ))
-                                        }
-                                    }
-                                    Err(error) => {
-                                        let mut msg = message_clone.lock().unwrap();
-                                        msg.error = Some(error);
-                                        if tx
-                                            .try_send(String::from("Failed to Encrypt file"))
-                                            .is_err()
-                                        {
-                                            msg.error = Some(Error::new(
-                                                ErrorKind::Other,
-                                                "Failed to refresh",
-                                            ))
-                                        }
+                        request_key(file, &mut file_struct, tx, CryptoAction::AgeEncrypt(path));
+                    } else {
+                        file_struct.error =
+                            Some(Error::new(ErrorKind::IsADirectory, "Not allowed!"))
+                    }
+                }
+            }
+            KeyCode::Char('d') | KeyCode::Char('D') => {
+                if !file_struct.marked.is_empty() {
+                    let paths: Vec<PathBuf> = file_struct.marked.iter().cloned().collect();
+                    let config = &file.config.encryption;
+                    // One passphrase is asked for, and tried on every file
+                    // that was made with one.
+                    let needs_passphrase = paths.iter().any(|path| {
+                        detect_format(path)
+                            .and_then(|format| opening_source(config, path, format))
+                            .is_ok_and(|source| source.is_none())
+                    });
+                    match file.keys.passphrase() {
+                        _ if !needs_passphrase => {
+                            decrypt_batch(file, &mut file_struct, tx, paths, None)
+                        }
+                        Some(passphrase) => {
+                            decrypt_batch(file, &mut file_struct, tx, paths, Some(passphrase))
+                        }
+                        None => {
+                            file.pending = Some(CryptoAction::DecryptBatch(paths));
+                            file.mode = ViewMode::Passphrase;
+                        }
+                    }
+                } else if let Some(index) = file_struct.current_state.selected() {
+                    let path = file_struct.current_dir[index].to_path_buf();
+                    if !path.is_file() {
+                        file_struct.error =
+                            Some(Error::new(ErrorKind::IsADirectory, "Not allowed!"))
+                    } else {
+                        match detect_format(&path) {
+                            Ok(Format::FileScout)
+                                if read_header(&path)
+                                    .is_ok_and(|header| header.has_sealed_name()) =>
+                            {
+                                // Names that aren't a plain file name are
+                                // left to `decrypt_into` to refuse.
+                                let name = file_struct
+                                    .real_names
+                                    .get(&path)
+                                    .and_then(|name| Path::new(name).file_name());
+                                match name.map(OsStr::to_os_string) {
+                                    Some(name) => {
+                                        let action = CryptoAction::Decrypt {
+                                            output: path.with_file_name(name),
+                                            path,
+                                            overwrite: false,
+                                            format: Format::FileScout,
+                                        };
+                                        confirm_overwrite(file, &mut file_struct, tx, action)
                                     }
+                                    None => request_key(
+                                        file,
+                                        &mut file_struct,
+                                        tx,
+                                        CryptoAction::DecryptSealed(path),
+                                    ),
                                 }
-                            });
+                            }
+                            Ok(format) => {
+                                // age files don't record a name to suggest.
+                                let suggestion = match format {
+                                    Format::FileScout => {
+                                        read_header(&path).ok().map(|header| header.name)
+                                    }
+                                    _ => None,
+                                };
+                                choose_output(
+                                    file,
+                                    &mut file_struct,
+                                    tx,
+                                    path,
+                                    suggestion.as_deref(),
+                                    format,
+                                )
+                            }
+                            Err(error) => file_struct.error = Some(error),
                         }
+                    }
+                }
+            }
+            KeyCode::Char('p') | KeyCode::Char('P') => {
+                if let Some(index) = file_struct.current_state.selected() {
+                    let path = file_struct.current_dir[index].to_path_buf();
+                    match detect_format(&path) {
+                        Ok(format) => request_key(
+                            file,
+                            &mut file_struct,
+                            tx,
+                            CryptoAction::Preview { path, format },
+                        ),
+                        Err(error) => file_struct.error = Some(error),
+                    }
+                }
+            }
+            KeyCode::Char('v') | KeyCode::Char('V') => {
+                if let Some(index) = file_struct.current_state.selected() {
+                    let path = file_struct.current_dir[index].to_path_buf();
+                    if !path.is_file() {
+                        file_struct.error =
+                            Some(Error::new(ErrorKind::IsADirectory, "Not allowed!"))
                     } else {
-                        file_struct.error = Some(Error::new(
-                            ErrorKind::IsADirectory,
-                            "can't encrypt directory",
-                        ))
+                        match detect_format(&path) {
+                            Ok(format) => request_key(
+                                file,
+                                &mut file_struct,
+                                tx,
+                                CryptoAction::Verify { path, format },
+                            ),
+                            Err(error) => file_struct.error = Some(error),
+                        }
                     }
                 }
             }
-            KeyCode::Char('d') | KeyCode::Char('D') => {
+            KeyCode::Char('k') | KeyCode::Char('K') => {
+                let marked = !file_struct.marked.is_empty();
+                let selection = if marked {
+                    Some((
+                        file_struct.marked.iter().cloned().collect(),
+                        format!("{} marked entries", file_struct.marked.len()),
+                    ))
+                } else {
+                    file_struct.current_state.selected().map(|index| {
+                        let path = file_struct.current_dir[index].to_path_buf();
+                        let name = path.file_name().unwrap_or_default().to_string_lossy();
+                        let name = name.into_owned();
+                        (vec![path], name)
+                    })
+                };
+                if let Some((paths, under)) = selection {
+                    find_rekey_targets(file, paths, under, marked, tx);
+                }
+            }
+            KeyCode::Char('w') | KeyCode::Char('W') => {
                 if let Some(index) = file_struct.current_state.selected() {
                     let path = file_struct.current_dir[index].to_path_buf();
                     if path.is_file() {
-                        if let Some(file_name) = path.file_name() {
-                            let file_name = file_name
-                                .to_str()
-                                .unwrap()
-                                .strip_suffix(".enc")
-                                .unwrap()
-                                .to_string();
-                            let mut output_path = file_struct.pwd.to_path_buf();
-                            output_path.push(file_name);
-                            let message_clone = Arc::clone(&file.files);
-                            thread::spawn(move || {
-                                match AesEncryptor::new().decrypt_file(&path, &output_path) {
-                                    Ok(()) => {
-                                        let mut msg = message_clone.lock().unwrap();
-                                        let pwd = msg.pwd.to_path_buf();
-                                        if let Some(index) = msg.current_state.selected() {
-                                            msg.present_dir_fn(&pwd, Some(index));
-                                        }
-                                        if tx
-                                            .try_send(String::from("File Decryption completed"))
-                                            .is_err()
-                                        {
-                                            msg.error = Some(Error::new(
-                                                ErrorKind::Other,
-                                                "Failed to refresh",
-                                            ))
-                                        }
-                                    }
-                                    Err(error) => {
-                                        let mut msg = message_clone.lock().unwrap();
-                                        msg.error = Some(error);
-                                        if tx
-                                            .try_send(String::from("Failed to Decrypt file"))
-                                            .is_err()
-                                        {
-                                            msg.error = Some(Error::new(
-                                                ErrorKind::Other,
-                                                "Failed to refresh",
-                                            ))
-                                        }
-                                    }
-                                }
-                            });
-                        }
+                        file.prompt = format!(
+                            "Encrypt {} and wipe the original?",
+                            path.file_name().unwrap().to_string_lossy()
+                        );
+                        file.pending = Some(CryptoAction::EncryptAndRemove(path));
+                        file.mode = ViewMode::Confirm;
+                    } else {
+                        file_struct.error =
+                            Some(Error::new(ErrorKind::IsADirectory, "Not allowed!"))
+                    }
+                }
+            }
+            KeyCode::Char('l') if modifier.contains(KeyModifiers::CONTROL) => {
+                file.keys.lock();
+                file_struct.forget_decrypted();
+                file.status = Some(String::from("Locked"));
+            }
+            KeyCode::Char('l') | KeyCode::Char('L') => {
+                if let Some(index) = file_struct.current_state.selected() {
+                    let path = file_struct.current_dir[index].to_path_buf();
+                    if path.is_file() {
+                        choose_output(file, &mut file_struct, tx, path, None, Format::Legacy);
                     } else {
                         file_struct.error =
                             Some(Error::new(ErrorKind::IsADirectory, "Not allowed!"))
                     }
                 }
             }
+            KeyCode::Char(' ') => {
+                if let Some(index) = file_struct.current_state.selected() {
+                    let path = file_struct.current_dir[index].to_path_buf();
+                    if !file_struct.marked.remove(&path) {
+                        file_struct.marked.insert(path);
+                    }
+                }
+            }
+            KeyCode::Char('/') => file.mode = ViewMode::Filter,
+            KeyCode::Char('f') | KeyCode::Char('F') => {
+                let root = file_struct.pwd.to_path_buf();
+                file.finder = Some(Finder::start(root, &file_struct.filter, tx));
+                file.mode = ViewMode::Finder;
+            }
+            KeyCode::Char('g') | KeyCode::Char('G') => {
+                // The last search comes back while still below where it ran.
+                if file
+                    .search
+                    .as_ref()
+                    .is_none_or(|search| !file_struct.pwd.starts_with(&search.root))
+                {
+                    file.search = Some(Search::new(file_struct.pwd.to_path_buf()));
+                }
+                file.mode = ViewMode::Search;
+            }
+            KeyCode::Esc if file_struct.narrowed.is_some() => file_struct.widen(),
+            KeyCode::Esc => file_struct.marked.clear(),
+            KeyCode::Char('h') | KeyCode::Char('H') => {
+                file_struct.filter.show_hidden = !file_struct.filter.show_hidden;
+                file_struct.reload();
+                file.status = Some(String::from(if file_struct.filter.show_hidden {
+                    "Showing hidden files"
+                } else {
+                    "Hiding hidden files"
+                }));
+            }
+            KeyCode::Char('i') | KeyCode::Char('I') => {
+                file_struct.filter.respect_ignore = !file_struct.filter.respect_ignore;
+                file_struct.reload();
+                file.status = Some(String::from(if file_struct.filter.respect_ignore {
+                    "Hiding ignored files"
+                } else {
+                    "Showing ignored files"
+                }));
+            }
+            KeyCode::Char('s') | KeyCode::Char('S') => {
+                let mut sort = file_struct.sort_for(&file_struct.pwd);
+                if code == KeyCode::Char('S') {
+                    sort.descending = !sort.descending;
+                } else {
+                    sort.key = sort.key.next();
+                }
+                file_struct.set_sort(sort);
+                file.status = Some(format!("Sorted by {sort}"));
+            }
+            KeyCode::Char('x') | KeyCode::Char('X') => {
+                let cancelled = file.jobs.cancel_latest();
+                if !cancelled {
+                    file.status = Some(String::from("No running jobs"));
+                }
+            }
             KeyCode::Char('c') | KeyCode::Char('C') => {
                 file.color_index = (file.color_index + 1) % COLORS.len()
             }
@@ -189,17 +371,9 @@ pub fn handle_events(
                             let path = file_struct.current_dir[index].to_path_buf();
                             file_struct.next_dir_fn(path.as_path());
                         } else if file_struct.current_dir.len() > index {
-                            file_struct.content = String::new();
+                            file_struct.content.zeroize();
                             let file_path = file_struct.current_dir[index].to_path_buf();
-                            let file = Arc::clone(&file.files);
-                            tokio::spawn(async move {
-                                let mut file_str = file.lock().unwrap();
-                                file_str.read_file(file_path);
-                                if tx.try_send(String::new()).is_err() {
-                                    file_str.error =
-                                        Some(Error::new(ErrorKind::Other, "something went wrong"))
-                                }
-                            });
+                            load_selected(file, file_path, tx);
                         } else {
                             return;
                         }
@@ -207,10 +381,10 @@ pub fn handle_events(
                             Some(file_struct.current_dir[index].to_path_buf());
                     }
                 }
-                ViewMode::ContentView => {
-                    if file.text_scroll_y < file_struct.line_count.saturating_sub(1) {
-                        file.text_scroll_y = file.text_scroll_y.saturating_add(1)
-                    }
+                ViewMode::ContentView
+                    if file.text_scroll_y < file_struct.line_count.saturating_sub(1) =>
+                {
+                    file.text_scroll_y = file.text_scroll_y.saturating_add(1)
                 }
                 _ => {}
             },
@@ -226,17 +400,9 @@ pub fn handle_events(
                             let path = file_struct.current_dir[index].to_path_buf();
                             file_struct.next_dir_fn(path.as_path());
                         } else if file_struct.current_dir.len() > index {
-                            file_struct.content = String::new();
+                            file_struct.content.zeroize();
                             let file_path = file_struct.current_dir[index].to_path_buf();
-                            let file = Arc::clone(&file.files);
-                            tokio::spawn(async move {
-                                let mut file_str = file.lock().unwrap();
-                                file_str.read_file(file_path);
-                                if tx.try_send(String::new()).is_err() {
-                                    file_str.error =
-                                        Some(Error::new(ErrorKind::Other, "something went wrong"))
-                                }
-                            });
+                            load_selected(file, file_path, tx);
                         }
                         file_struct.current_path =
                             Some(file_struct.current_dir[index].to_path_buf());
@@ -283,7 +449,15 @@ fn handle_file_edit(
     let mut line = file.input.content.lines();
     match (code, modifier) {
         (KeyCode::Char('s'), KeyModifiers::CONTROL) => {
-            file_struct.file_write(file.input.content.clone());
+            match &file.document {
+                Some(document) => {
+                    if let Err(error) = document.save(&file.input.content) {
+                        file_struct.error = Some(error);
+                        return;
+                    }
+                }
+                None => file_struct.file_write(file.input.content.clone()),
+            }
             let pwd = file_struct.pwd.to_path_buf();
             reset_mode(file);
             if let Some(index) = file_struct.current_state.selected() {
@@ -411,7 +585,1110 @@ fn handle_file_name(code: KeyCode, file: &mut FileScout, mut file_struct: MutexG
     }
 }
 
+fn handle_filter(code: KeyCode, file: &mut FileScout, mut file_struct: MutexGuard<FileStruct>) {
+    let mut query = file_struct
+        .narrowed
+        .as_ref()
+        .map(|narrowed| narrowed.query.clone())
+        .unwrap_or_default();
+    match code {
+        KeyCode::Char(c) => {
+            query.push(c);
+            file_struct.narrow(&query);
+        }
+        KeyCode::Backspace if !query.is_empty() => {
+            query.pop();
+            file_struct.narrow(&query);
+        }
+        KeyCode::Down => {
+            let last = file_struct.current_dir.len().saturating_sub(1);
+            if let Some(index) = file_struct.current_state.selected() {
+                file_struct.select(Some((index + 1).min(last)));
+            }
+        }
+        KeyCode::Up => {
+            if let Some(index) = file_struct.current_state.selected() {
+                file_struct.select(Some(index.saturating_sub(1)));
+            }
+        }
+        // The narrowed list stays until Esc or the directory changes.
+        KeyCode::Enter => {
+            if query.is_empty() {
+                file_struct.widen();
+            }
+            reset_mode(file);
+        }
+        KeyCode::Esc => {
+            file_struct.widen();
+            reset_mode(file);
+        }
+        _ => {}
+    }
+}
+
+fn handle_finder(code: KeyCode, file: &mut FileScout, mut file_struct: MutexGuard<FileStruct>) {
+    let Some(finder) = file.finder.as_mut() else {
+        return reset_mode(file);
+    };
+    match code {
+        KeyCode::Char(c) => {
+            let mut query = finder.query.clone();
+            query.push(c);
+            finder.set_query(query);
+        }
+        KeyCode::Backspace => {
+            let mut query = finder.query.clone();
+            query.pop();
+            finder.set_query(query);
+        }
+        KeyCode::Down => {
+            let last = finder.result_count().saturating_sub(1);
+            let index = finder.state.selected().unwrap_or_default();
+            finder.state.select(Some((index + 1).min(last)));
+        }
+        KeyCode::Up => {
+            let index = finder.state.selected().unwrap_or_default();
+            finder.state.select(Some(index.saturating_sub(1)));
+        }
+        KeyCode::Enter => {
+            if let Some(path) = finder.selected().map(Path::to_path_buf) {
+                if let Some(parent) = path.parent() {
+                    file_struct.present_dir_fn(parent, None);
+                    let index = file_struct
+                        .current_dir
+                        .iter()
+                        .position(|entry| *entry == path);
+                    if index.is_some() {
+                        file_struct.select(index);
+                    }
+                }
+            }
+            file.finder = None;
+            reset_mode(file);
+        }
+        KeyCode::Esc => {
+            // Dropping the finder stops its walk.
+            file.finder = None;
+            reset_mode(file);
+        }
+        _ => {}
+    }
+}
+
+fn handle_search(
+    code: KeyCode,
+    file: &mut FileScout,
+    mut file_struct: MutexGuard<FileStruct>,
+    tx: Sender<Message>,
+) {
+    let Some(search) = file.search.as_mut() else {
+        return reset_mode(file);
+    };
+    match code {
+        KeyCode::Char(c) => search.pattern.push(c),
+        KeyCode::Backspace => {
+            search.pattern.pop();
+        }
+        KeyCode::Tab => search.regex = !search.regex,
+        KeyCode::Down => {
+            let last = search.hits().len().saturating_sub(1);
+            if let Some(index) = search.state.selected() {
+                search.state.select(Some((index + 1).min(last)));
+            }
+        }
+        KeyCode::Up => {
+            if let Some(index) = search.state.selected() {
+                search.state.select(Some(index.saturating_sub(1)));
+            }
+        }
+        // Runs what is typed, or opens the selected match once it has run.
+        KeyCode::Enter if search.pattern.is_empty() => {}
+        KeyCode::Enter if !search.is_current() => {
+            if let Err(error) = search.start(&file_struct.filter, tx) {
+                file_struct.error = Some(error);
+            }
+        }
+        KeyCode::Enter => {
+            let Some(hit) = search.selected() else {
+                return;
+            };
+            let (path, line) = (hit.path.to_path_buf(), hit.line);
+            if let Some(parent) = path.parent() {
+                file_struct.present_dir_fn(parent, None);
+            }
+            let index = file_struct
+                .current_dir
+                .iter()
+                .position(|entry| *entry == path);
+            if index.is_none() {
+                file_struct.error = Some(Error::new(
+                    ErrorKind::NotFound,
+                    format!("{} is gone", path.display()),
+                ));
+                return;
+            }
+            file_struct.select(index);
+            reset_mode(file);
+            if file.mode == ViewMode::ListView {
+                file.mode = ViewMode::ContentView;
+                file.text_scroll_x = 0;
+                file.text_scroll_y = line.saturating_sub(CONTEXT + 1);
+            }
+        }
+        KeyCode::Esc => {
+            // Dropping the search stops its run.
+            file.search = None;
+            reset_mode(file);
+        }
+        _ => {}
+    }
+}
+
+fn handle_passphrase(
+    code: KeyCode,
+    file: &mut FileScout,
+    mut file_struct: MutexGuard<FileStruct>,
+    tx: Sender<Message>,
+) {
+    match code {
+        KeyCode::Char(c) => file.input.content.push(c),
+        KeyCode::Backspace => {
+            file.input.content.pop();
+        }
+        // An empty passphrase would still derive a key.
+        KeyCode::Enter if !file.input.content.is_empty() => {
+            let passphrase = Zeroizing::new(std::mem::take(&mut file.input.content));
+            // Re-keying first asks for the passphrase the files were made
+            // with, then for a new one unless a key is configured.
+            if let Some(CryptoAction::Rekey {
+                needs_old: true,
+                old: old @ None,
+                ..
+            }) = &mut file.pending
+            {
+                *old = Some(passphrase);
+                if let Some(source) = file.config.encryption.key_source() {
+                    let action = file.pending.take();
+                    reset_mode(file);
+                    if let Some(action) = action {
+                        run_crypto_action(file, &mut file_struct, tx, action, source);
+                    }
+                }
+                return;
+            }
+            // A typo in a new passphrase would lock the data away for good,
+            // so encrypting asks for it twice.
+            let encrypting = matches!(
+                file.pending,
+                Some(
+                    CryptoAction::Encrypt(_)
+                        | CryptoAction::EncryptBatch(_)
+                        | CryptoAction::EncryptAndRemove(_)
+                        | CryptoAction::AgeEncrypt(_)
+                        | CryptoAction::Rekey { .. }
+                )
+            );
+            if encrypting {
+                match file.first_passphrase.take() {
+                    None => {
+                        file.first_passphrase = Some(passphrase);
+                        return;
+                    }
+                    Some(first) if first != passphrase => {
+                        file_struct.error = Some(Error::new(
+                            ErrorKind::InvalidInput,
+                            "Passphrases don't match",
+                        ));
+                        reset_mode(file);
+                        return;
+                    }
+                    Some(_) => {}
+                }
+                unlock_session(file, passphrase.clone(), &tx);
+            }
+            let action = file.pending.take();
+            reset_mode(file);
+            if let Some(action) = action {
+                run_crypto_action(
+                    file,
+                    &mut file_struct,
+                    tx,
+                    action,
+                    KeySource::Passphrase(passphrase),
+                );
+            }
+        }
+        KeyCode::Esc => {
+            file.input.content.zeroize();
+            reset_mode(file)
+        }
+        _ => {}
+    }
+}
+
+fn handle_confirm(
+    code: KeyCode,
+    file: &mut FileScout,
+    mut file_struct: MutexGuard<FileStruct>,
+    tx: Sender<Message>,
+) {
+    match code {
+        KeyCode::Char('y') | KeyCode::Char('Y') | KeyCode::Enter => {
+            let action = file.pending.take();
+            reset_mode(file);
+            if let Some(action) = action {
+                request_key(file, &mut file_struct, tx, action);
+            }
+        }
+        KeyCode::Char('n') | KeyCode::Char('N') | KeyCode::Esc => reset_mode(file),
+        _ => {}
+    }
+}
+
+fn handle_report(code: KeyCode, file: &mut FileScout) {
+    match code {
+        KeyCode::Esc | KeyCode::Enter => {
+            file.report.clear();
+            file.text_scroll_y = 0;
+            file.mode = ViewMode::ListView;
+        }
+        KeyCode::Down if file.text_scroll_y < file.report.len().saturating_sub(1) => {
+            file.text_scroll_y += 1
+        }
+        KeyCode::Up => file.text_scroll_y = file.text_scroll_y.saturating_sub(1),
+        _ => {}
+    }
+}
+
+fn handle_save_as(
+    code: KeyCode,
+    file: &mut FileScout,
+    mut file_struct: MutexGuard<FileStruct>,
+    tx: Sender<Message>,
+) {
+    match code {
+        KeyCode::Char(c) => file.input.content.push(c),
+        KeyCode::Backspace => {
+            file.input.content.pop();
+        }
+        KeyCode::Enter if !is_file_name(&file.input.content) => {
+            file_struct.error = Some(Error::new(
+                ErrorKind::InvalidInput,
+                "Give a name in this directory",
+            ));
+        }
+        KeyCode::Enter => {
+            file_struct.error = None;
+            let output = file_struct.pwd.join(&file.input.content);
+            let action = file.pending.take();
+            reset_mode(file);
+            if let Some(CryptoAction::Decrypt {
+                path,
+                overwrite,
+                format,
+                ..
+            }) = action
+            {
+                let action = CryptoAction::Decrypt {
+                    path,
+                    output,
+                    overwrite,
+                    format,
+                };
+                confirm_overwrite(file, &mut file_struct, tx, action);
+            }
+        }
+        KeyCode::Esc => reset_mode(file),
+        _ => {}
+    }
+}
+
+/// Decrypts `path` next to itself without its `.enc` or `.age` suffix. Files
+/// without one are recognised by their header instead, so the user is asked
+/// for a name, starting from `suggestion`.
+fn choose_output(
+    file: &mut FileScout,
+    file_struct: &mut FileStruct,
+    tx: Sender<Message>,
+    path: PathBuf,
+    suggestion: Option<&str>,
+    format: Format,
+) {
+    let stripped = decrypted_name(&path, format);
+    let action = CryptoAction::Decrypt {
+        output: stripped.clone().unwrap_or_else(|| path.with_file_name("")),
+        path,
+        overwrite: false,
+        format,
+    };
+    if stripped.is_some() {
+        confirm_overwrite(file, file_struct, tx, action);
+    } else {
+        // The header name comes from the file, so only its last component
+        // is trusted.
+        file.input.content = suggestion
+            .and_then(|name| Path::new(name).file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        file.pending = Some(action);
+        file.mode = ViewMode::SaveAs;
+    }
+}
+
+/// Whether `name` is one plain file name, which can only land in the
+/// directory it is joined to.
+fn is_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(part)), None) if part == name
+    )
+}
+
+/// `path` without its `.enc` or `.age` suffix, if it has one.
+fn decrypted_name(path: &Path, format: Format) -> Option<PathBuf> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(format.extension()))
+        .filter(|name| !name.is_empty())
+        .map(|name| path.with_file_name(name))
+}
+
+/// Asks before a decryption replaces something that already exists.
+fn confirm_overwrite(
+    file: &mut FileScout,
+    file_struct: &mut FileStruct,
+    tx: Sender<Message>,
+    mut action: CryptoAction,
+) {
+    if let CryptoAction::Decrypt {
+        output, overwrite, ..
+    } = &mut action
+    {
+        if !*overwrite && fs::symlink_metadata(&*output).is_ok() {
+            file.prompt = format!(
+                "{} already exists, overwrite it?",
+                output.file_name().unwrap_or_default().to_string_lossy()
+            );
+            *overwrite = true;
+            file.pending = Some(action);
+            file.mode = ViewMode::Confirm;
+            return;
+        }
+    }
+    request_key(file, file_struct, tx, action);
+}
+
+/// Runs `action` with the key source from the config, or the session's
+/// passphrase, and asks for a passphrase when there is neither.
+fn request_key(
+    file: &mut FileScout,
+    file_struct: &mut FileStruct,
+    tx: Sender<Message>,
+    mut action: CryptoAction,
+) {
+    let config = &file.config.encryption;
+    let mut cached = file.keys.passphrase();
+    let needed = match &mut action {
+        CryptoAction::Decrypt { path, format, .. } | CryptoAction::Verify { path, format } => {
+            opening_source(config, path, *format)
+        }
+        CryptoAction::DecryptSealed(path) => opening_source(config, path, Format::FileScout),
+        CryptoAction::Edit(path) => opening_source(config, path, Format::FileScout),
+        CryptoAction::Preview { path, format } => opening_source(config, path, *format),
+        CryptoAction::AgeEncrypt(_) => Ok(config.age_source()),
+        // The session only stands in for the current passphrase; the new
+        // one is always asked for.
+        CryptoAction::Rekey { needs_old, old, .. } => {
+            let current = cached.take();
+            if *needs_old && old.is_none() {
+                *old = current;
+            }
+            Ok(config.key_source().filter(|_| !*needs_old || old.is_some()))
+        }
+        _ => Ok(config.key_source()),
+    }
+    .map(|source| source.or_else(|| cached.map(KeySource::Passphrase)));
+    let source = match needed {
+        Ok(source) => source,
+        Err(error) => {
+            file_struct.error = Some(error);
+            return;
+        }
+    };
+    match source {
+        Some(source) => run_crypto_action(file, file_struct, tx, action, source),
+        None => {
+            file.pending = Some(action);
+            file.mode = ViewMode::Passphrase;
+        }
+    }
+}
+
+/// Opening a file uses the kind of key it was made with, so only files that
+/// need a passphrase ask for one.
+fn opening_source(
+    config: &EncryptionConfig,
+    path: &Path,
+    format: Format,
+) -> Result<Option<KeySource>> {
+    match format {
+        Format::Legacy => Ok(Some(KeySource::Legacy)),
+        Format::Age => {
+            if needs_passphrase(path)? {
+                return Ok(None);
+            }
+            config
+                .identity_source()
+                .map(Some)
+                .ok_or_else(|| CryptoError::WrongKeyType("an identity file").into())
+        }
+        Format::FileScout => match read_header(path)?.kdf {
+            Kdf::Argon2id { .. } => Ok(None),
+            kdf => config
+                .source_for(&kdf)
+                .map(Some)
+                .ok_or_else(|| CryptoError::WrongKeyType(kdf.key_name()).into()),
+        },
+    }
+}
+
+fn run_crypto_action(
+    file: &mut FileScout,
+    file_struct: &mut FileStruct,
+    tx: Sender<Message>,
+    action: CryptoAction,
+    source: KeySource,
+) {
+    let cipher = file.config.encryption.cipher;
+    let hide_names = file.config.encryption.hide_names;
+    let mut output = file_struct.pwd.to_path_buf();
+    match action {
+        CryptoAction::Encrypt(path) => {
+            let encryptor = AesEncryptor::new(source, cipher).hide_names(hide_names);
+            if let Some(file_name) = path.file_name() {
+                output.push(encrypted_name(file_name, hide_names));
+                let file_name = file_name.to_string_lossy();
+                if path.is_dir() {
+                    spawn_job(
+                        file,
+                        tx,
+                        format!("Encrypting {file_name}"),
+                        move |job| encryptor.encrypt_dir(&path, &output, job),
+                        "Directory Encryption completed",
+                        "Failed to Encrypt directory",
+                    );
+                } else {
+                    spawn_job(
+                        file,
+                        tx,
+                        format!("Encrypting {file_name}"),
+                        move |job| encryptor.encrypt_file(&path, &output, job),
+                        "File Encryption completed",
+                        "Failed to Encrypt file",
+                    );
+                }
+            }
+        }
+        CryptoAction::EncryptBatch(paths) => {
+            let unlocker = Unlocker::new(&tx, &source, &file.keys);
+            let encryptor = AesEncryptor::new(source, cipher).hide_names(hide_names);
+            file_struct.marked.clear();
+            spawn_batch(
+                file,
+                unlocker,
+                format!("Encrypting {} file(s)", paths.len()),
+                "Encrypted",
+                paths,
+                output,
+                move |path, progress| {
+                    let file_name = path.file_name().unwrap_or_default();
+                    let output = path.with_file_name(encrypted_name(file_name, hide_names));
+                    if path.is_dir() {
+                        encryptor.encrypt_dir(path, &output, progress)?;
+                    } else {
+                        encryptor.encrypt_file(path, &output, progress)?;
+                    }
+                    Ok(format!(
+                        "encrypted to {}",
+                        output.file_name().unwrap_or_default().to_string_lossy()
+                    ))
+                },
+            );
+        }
+        CryptoAction::DecryptBatch(paths) => {
+            let passphrase = match source {
+                KeySource::Passphrase(passphrase) => Some(passphrase),
+                _ => None,
+            };
+            decrypt_batch(file, file_struct, tx, paths, passphrase);
+        }
+        CryptoAction::EncryptAndRemove(path) => {
+            let encryptor = AesEncryptor::new(source, cipher).hide_names(hide_names);
+            if let Some(file_name) = path.file_name() {
+                output.push(encrypted_name(file_name, hide_names));
+                let file_name = file_name.to_string_lossy();
+                spawn_job(
+                    file,
+                    tx,
+                    format!("Encrypting and wiping {file_name}"),
+                    move |job| encryptor.encrypt_and_remove(&path, &output, job),
+                    "File Encrypted and original removed",
+                    "Failed to Encrypt file",
+                );
+            }
+        }
+        CryptoAction::AgeEncrypt(path) => {
+            let encryptor = AgeEncryptor::new(source);
+            if let Some(file_name) = path.file_name() {
+                let mut name = file_name.to_os_string();
+                name.push(".age");
+                output.push(name);
+                let file_name = file_name.to_string_lossy();
+                spawn_job(
+                    file,
+                    tx,
+                    format!("Encrypting {file_name} to age"),
+                    move |job| encryptor.encrypt_file(&path, &output, job),
+                    "File Encryption completed",
+                    "Failed to Encrypt file",
+                );
+            }
+        }
+        CryptoAction::Decrypt {
+            path,
+            output,
+            overwrite,
+            format,
+        } => {
+            let file_name = output
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned();
+            let unlocker = Unlocker::new(&tx, &source, &file.keys);
+            spawn_job(
+                file,
+                tx,
+                format!("Decrypting {file_name}"),
+                move |job| {
+                    unlocker.check(match format {
+                        Format::Age => {
+                            AgeEncryptor::new(source).decrypt_file(&path, &output, overwrite, job)
+                        }
+                        Format::FileScout | Format::Legacy => AesEncryptor::new(source, cipher)
+                            .decrypt_file(&path, &output, overwrite, job),
+                    })
+                },
+                "File Decryption completed",
+                "Failed to Decrypt file",
+            );
+        }
+        CryptoAction::DecryptSealed(path) => {
+            let unlocker = Unlocker::new(&tx, &source, &file.keys);
+            let files = Arc::clone(&file.files);
+            spawn_job(
+                file,
+                tx,
+                String::from("Decrypting"),
+                move |job| {
+                    let output = unlocker.check(
+                        AesEncryptor::new(source, cipher).decrypt_into(&path, &output, false, job),
+                    )?;
+                    reveal_name(&files, path, &output);
+                    Ok(())
+                },
+                "File Decryption completed",
+                "Failed to Decrypt file",
+            );
+        }
+        CryptoAction::Edit(path) => {
+            let unlocker = Unlocker::new(&tx, &source, &file.keys);
+            let sender = tx.clone();
+            spawn_job(
+                file,
+                tx,
+                String::from("Opening for editing"),
+                move |job| {
+                    let (document, content) = unlocker
+                        .check(AesEncryptor::new(source, cipher).open_document(&path, job))?;
+                    let _ = sender.blocking_send(Message::Document(document, content));
+                    Ok(())
+                },
+                "Opened for editing",
+                "Failed to open file",
+            );
+        }
+        CryptoAction::Preview { path, format } => {
+            let unlocker = Unlocker::new(&tx, &source, &file.keys);
+            let files = Arc::clone(&file.files);
+            thread::spawn(move || {
+                let result = match format {
+                    Format::Age => AgeEncryptor::new(source)
+                        .preview(&path, PREVIEW_LIMIT)
+                        .map(|content| (None, content)),
+                    Format::FileScout | Format::Legacy => {
+                        AesEncryptor::new(source, cipher).preview(&path, PREVIEW_LIMIT)
+                    }
+                };
+                unlocker.settle(fitted(&result));
+                let mut file_struct = files.lock().unwrap();
+                // Only shown if the file is still selected.
+                if let Ok((Some(name), _)) = &result {
+                    file_struct.real_names.insert(path.clone(), name.clone());
+                }
+                if file_struct.current_path.as_deref() == Some(path.as_path()) {
+                    match result {
+                        Ok((_, content)) => {
+                            file_struct.line_count = content.lines().count();
+                            file_struct.content = content;
+                        }
+                        Err(error) => file_struct.error = Some(error),
+                    }
+                }
+                drop(file_struct);
+                let _ = tx.blocking_send(Message::Refresh);
+            });
+        }
+        CryptoAction::Verify { path, format } => {
+            let file_name = path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned();
+            let unlocker = Unlocker::new(&tx, &source, &file.keys);
+            let files = Arc::clone(&file.files);
+            spawn_job(
+                file,
+                tx,
+                format!("Verifying {file_name}"),
+                move |job| {
+                    let name = unlocker.check(match format {
+                        Format::Age => AgeEncryptor::new(source).verify(&path, job).map(|_| None),
+                        Format::FileScout | Format::Legacy => {
+                            AesEncryptor::new(source, cipher).verify(&path, job)
+                        }
+                    })?;
+                    if let Some(name) = name {
+                        files.lock().unwrap().real_names.insert(path, name);
+                    }
+                    Ok(())
+                },
+                "File is intact",
+                "File failed verification",
+            );
+        }
+        CryptoAction::Rekey { targets, old, .. } => {
+            // It is the current passphrase that is tried on the files. Once
+            // it fits they are under the new one, so that is remembered.
+            let unlocker = Unlocker {
+                tx: tx.clone(),
+                passphrase: old.clone(),
+                remember: match &source {
+                    KeySource::Passphrase(new) => Some(new.clone()),
+                    _ => None,
+                },
+                generation: file.keys.generation(),
+            };
+            file_struct.marked.clear();
+            let new = AesEncryptor::new(source, cipher);
+            let config = file.config.encryption.clone();
+            spawn_batch(
+                file,
+                unlocker,
+                format!("Re-keying {} file(s)", targets.len()),
+                "Re-keyed",
+                targets,
+                output,
+                move |path, progress| {
+                    let source = match (opening_source(&config, path, Format::FileScout)?, &old) {
+                        (Some(source), _) => source,
+                        (None, Some(old)) => KeySource::Passphrase(old.clone()),
+                        (None, None) => {
+                            return Err(CryptoError::WrongKeyType("a passphrase").into())
+                        }
+                    };
+                    AesEncryptor::new(source, cipher).rekey(path, &new, progress)?;
+                    Ok(String::from("re-keyed"))
+                },
+            );
+        }
+    }
+}
+
+/// Decrypts each of `paths` next to itself, using the configured key that
+/// matches each file or else `passphrase`. Nothing is overwritten, as there
+/// is nobody to ask file by file.
+fn decrypt_batch(
+    file: &mut FileScout,
+    file_struct: &mut FileStruct,
+    tx: Sender<Message>,
+    paths: Vec<PathBuf>,
+    passphrase: Option<Zeroizing<String>>,
+) {
+    let cipher = file.config.encryption.cipher;
+    let config = file.config.encryption.clone();
+    file_struct.marked.clear();
+    let unlocker = Unlocker {
+        tx: tx.clone(),
+        passphrase: passphrase.clone(),
+        remember: None,
+        generation: file.keys.generation(),
+    };
+    let files = Arc::clone(&file.files);
+    spawn_batch(
+        file,
+        unlocker,
+        format!("Decrypting {} file(s)", paths.len()),
+        "Decrypted",
+        paths,
+        file_struct.pwd.to_path_buf(),
+        move |path, progress| {
+            let format = detect_format(path)?;
+            let source = match (opening_source(&config, path, format)?, &passphrase) {
+                (Some(source), _) => source,
+                (None, Some(passphrase)) => KeySource::Passphrase(passphrase.clone()),
+                (None, None) => return Err(CryptoError::WrongKeyType("a passphrase").into()),
+            };
+            let output = if format == Format::FileScout && read_header(path)?.has_sealed_name() {
+                let dir = path.parent().unwrap_or(Path::new(""));
+                let output =
+                    AesEncryptor::new(source, cipher).decrypt_into(path, dir, false, progress)?;
+                reveal_name(&files, path.to_path_buf(), &output);
+                output
+            } else {
+                let output = decrypted_name(path, format).ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Name doesn't end in {}", format.extension()),
+                    )
+                })?;
+                match format {
+                    Format::Age => {
+                        AgeEncryptor::new(source).decrypt_file(path, &output, false, progress)?
+                    }
+                    Format::FileScout | Format::Legacy => AesEncryptor::new(source, cipher)
+                        .decrypt_file(path, &output, false, progress)?,
+                }
+                output
+            };
+            Ok(format!(
+                "decrypted to {}",
+                output.file_name().unwrap_or_default().to_string_lossy()
+            ))
+        },
+    );
+}
+
+/// Lists `path` under the name of `output`, which it was decrypted to.
+fn reveal_name(files: &Mutex<FileStruct>, path: PathBuf, output: &Path) {
+    let name = output.file_name().unwrap_or_default().to_string_lossy();
+    files
+        .lock()
+        .unwrap()
+        .real_names
+        .insert(path, name.into_owned());
+}
+
+/// Looks for the files to re-key under `paths` in a background job, then
+/// asks whether to go ahead. Marked files that aren't encrypted are left
+/// out rather than failing the whole batch.
+fn find_rekey_targets(
+    file: &mut FileScout,
+    paths: Vec<PathBuf>,
+    under: String,
+    marked: bool,
+    tx: Sender<Message>,
+) {
+    let job = file.jobs.start(
+        format!("Looking for encrypted files under {under}"),
+        tx.clone(),
+    );
+    let files = Arc::clone(&file.files);
+    thread::spawn(move || {
+        let mut found = Vec::new();
+        let mut result = Ok(());
+        for path in &paths {
+            match encrypted_files(path, &job) {
+                Ok(targets) => found.extend(targets),
+                Err(error)
+                    if marked
+                        && matches!(CryptoError::of(&error), Some(CryptoError::NotEncrypted)) => {}
+                Err(error) => {
+                    result = Err(error);
+                    break;
+                }
+            }
+        }
+        let message = match result {
+            Err(error) if is_cancelled(&error) => "Job cancelled",
+            Err(error) => {
+                files.lock().unwrap().error = Some(error);
+                "Failed to look for encrypted files"
+            }
+            Ok(()) if found.is_empty() => "No encrypted files found",
+            Ok(()) => {
+                let needs_old = found
+                    .iter()
+                    .any(|(_, kdf)| matches!(kdf, Kdf::Argon2id { .. }));
+                let prompt = format!("Re-key {} encrypted file(s) under {under}?", found.len());
+                let action = CryptoAction::Rekey {
+                    targets: found.into_iter().map(|(path, _)| path).collect(),
+                    needs_old,
+                    old: None,
+                };
+                let _ = tx.blocking_send(Message::Confirm(prompt, action));
+                ""
+            }
+        };
+        job.finish(String::from(message));
+    });
+}
+
+/// Reads the file at `path` into the next pane as a background job, so a
+/// big one shows its progress and can be cancelled with `X`.
+fn load_selected(file: &mut FileScout, path: PathBuf, tx: Sender<Message>) {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let job = file.jobs.start(format!("Loading {name}"), tx);
+    let files = Arc::clone(&file.files);
+    thread::spawn(move || {
+        let loaded = load_file(&path, &job);
+        let cancelled = loaded.as_ref().is_err_and(is_cancelled);
+        {
+            let mut file_struct = files.lock().unwrap();
+            // Only shown if the file is still selected.
+            if !cancelled && file_struct.current_path.as_deref() == Some(path.as_path()) {
+                file_struct.show_file(&path, loaded);
+            }
+        }
+        // Loads that went through finish quietly.
+        let message = if cancelled { "Job cancelled" } else { "" };
+        job.finish(String::from(message));
+    });
+}
+
+/// Opens the editor on a decrypted `document`. If a prompt was opened while
+/// it was decrypting, the text is wiped instead.
+pub fn open_editor(file: &mut FileScout, document: EncryptedDocument, mut content: String) {
+    if !matches!(file.mode, ViewMode::ListView | ViewMode::ContentView) {
+        content.zeroize();
+        return;
+    }
+    file.input.total_lines = content.lines().count();
+    file.input.total_letter = content.lines().next().unwrap_or_default().len();
+    file.input.content = content;
+    file.document = Some(document);
+    file.mode = ViewMode::FileEdit;
+}
+
+/// Remembers `passphrase` for the session. When it is new, the hidden names
+/// in the current directory that it opens are unsealed in the background,
+/// so the listing shows them.
+pub fn unlock_session(file: &mut FileScout, passphrase: Zeroizing<String>, tx: &Sender<Message>) {
+    if file.keys.passphrase().as_ref() == Some(&passphrase) {
+        file.keys.touch();
+        return;
+    }
+    file.keys.unlock(passphrase.clone());
+    let files = Arc::clone(&file.files);
+    let cipher = file.config.encryption.cipher;
+    let tx = tx.clone();
+    thread::spawn(move || {
+        let paths: Vec<PathBuf> = {
+            let file_struct = files.lock().unwrap();
+            file_struct
+                .current_dir
+                .iter()
+                .filter(|path| !file_struct.real_names.contains_key(*path))
+                .cloned()
+                .collect()
+        };
+        for path in paths {
+            let sealed = read_header(&path).is_ok_and(|header| {
+                header.has_sealed_name() && matches!(header.kdf, Kdf::Argon2id { .. })
+            });
+            if !sealed {
+                continue;
+            }
+            let source = KeySource::Passphrase(passphrase.clone());
+            if let Ok(Some(name)) = AesEncryptor::new(source, cipher).sealed_name(&path) {
+                files.lock().unwrap().real_names.insert(path, name);
+                let _ = tx.blocking_send(Message::Refresh);
+            }
+        }
+    });
+}
+
+/// Name of the `.enc` file made from `file_name`, a random one when names
+/// are hidden.
+fn encrypted_name(file_name: &OsStr, hide_names: bool) -> OsString {
+    if hide_names {
+        OsString::from(random_name())
+    } else {
+        let mut name = file_name.to_os_string();
+        name.push(".enc");
+        name
+    }
+}
+
+/// Runs `task` as a background job on its own thread. The task reports
+/// progress through the `JobHandle` and its outcome is posted once it returns.
+fn spawn_job(
+    file: &mut FileScout,
+    tx: Sender<Message>,
+    label: String,
+    task: impl FnOnce(&JobHandle) -> Result<()> + Send + 'static,
+    success: &'static str,
+    failure: &'static str,
+) {
+    let job = file.jobs.start(label, tx);
+    let message_clone = Arc::clone(&file.files);
+    thread::spawn(move || {
+        let result = task(&job);
+        let message = {
+            let mut msg = message_clone.lock().unwrap();
+            match result {
+                Ok(()) => {
+                    let pwd = msg.pwd.to_path_buf();
+                    if let Some(index) = msg.current_state.selected() {
+                        msg.present_dir_fn(&pwd, Some(index));
+                    }
+                    success
+                }
+                Err(error) if is_cancelled(&error) => "Job cancelled",
+                Err(error) => {
+                    msg.error = Some(error);
+                    failure
+                }
+            }
+        };
+        if !job.finish(String::from(message)) {
+            message_clone.lock().unwrap().error = Some(Error::other("Failed to refresh"))
+        }
+    });
+}
+
+/// Runs `task` on each of `paths` as one background job, a few files at a
+/// time. Each file's outcome goes into a report shown once all are done,
+/// named relative to `root`. Messages go out through `unlocker`'s channel.
+fn spawn_batch(
+    file: &mut FileScout,
+    unlocker: Unlocker,
+    label: String,
+    verb: &'static str,
+    paths: Vec<PathBuf>,
+    root: PathBuf,
+    task: impl Fn(&Path, &BatchProgress) -> Result<String> + Send + Sync + 'static,
+) {
+    let tx = unlocker.tx.clone();
+    let job = file.jobs.start(label, tx.clone());
+    let files = Arc::clone(&file.files);
+    thread::spawn(move || {
+        let progress = BatchProgress::new(job, paths.len() as u64);
+        let results = run_pool(&paths, |path| {
+            progress.entry(path.strip_prefix(&root).unwrap_or(path));
+            // Files not started yet are skipped once the job is cancelled.
+            let result = progress.advance(0).and_then(|()| task(path, &progress));
+            progress.item_done();
+            result
+        });
+        unlocker.settle(results.iter().filter_map(fitted).min());
+        let mut done = 0;
+        let report = paths
+            .iter()
+            .zip(results)
+            .map(|(path, result)| {
+                let name = path.strip_prefix(&root).unwrap_or(path).display();
+                match result {
+                    Ok(outcome) => {
+                        done += 1;
+                        format!("{name}: {outcome}")
+                    }
+                    Err(error) if is_cancelled(&error) => format!("{name}: cancelled"),
+                    Err(error) => format!("{name}: failed, {error}"),
+                }
+            })
+            .collect();
+        {
+            let mut file_struct = files.lock().unwrap();
+            let pwd = file_struct.pwd.to_path_buf();
+            let index = file_struct.current_state.selected();
+            file_struct.present_dir_fn(&pwd, index);
+        }
+        let _ = tx.blocking_send(Message::Report(report));
+        let summary = format!("{verb} {done} of {} file(s)", paths.len());
+        if !progress.into_inner().finish(summary) {
+            files.lock().unwrap().error = Some(Error::other("Failed to refresh"))
+        }
+    });
+}
+
+/// Remembers a passphrase for the session once it has opened a file, and
+/// forgets the session's passphrase when one doesn't fit.
+struct Unlocker {
+    tx: Sender<Message>,
+    passphrase: Option<Zeroizing<String>>,
+    /// Remembered instead of `passphrase` once that fits.
+    remember: Option<Zeroizing<String>>,
+    /// Of the key cache when the job started.
+    generation: u64,
+}
+
+impl Unlocker {
+    fn new(tx: &Sender<Message>, source: &KeySource, keys: &KeyCache) -> Self {
+        let passphrase = match source {
+            KeySource::Passphrase(passphrase) => Some(passphrase.clone()),
+            _ => None,
+        };
+        Self {
+            tx: tx.clone(),
+            passphrase,
+            remember: None,
+            generation: keys.generation(),
+        }
+    }
+
+    /// Acts on whether the passphrase `fitted`, if that is known.
+    fn settle(&self, fitted: Option<bool>) {
+        let (Some(passphrase), Some(fitted)) = (&self.passphrase, fitted) else {
+            return;
+        };
+        let message = if fitted {
+            let remembered = self.remember.as_ref().unwrap_or(passphrase);
+            Message::Unlocked(remembered.clone(), self.generation)
+        } else {
+            Message::Forget(self.generation)
+        };
+        let _ = self.tx.blocking_send(message);
+    }
+
+    fn check<T>(&self, result: Result<T>) -> Result<T> {
+        self.settle(fitted(&result));
+        result
+    }
+}
+
+/// Whether the key of an operation with this `result` fitted, or `None` if
+/// it failed for some other reason.
+fn fitted<T>(result: &Result<T>) -> Option<bool> {
+    match result {
+        Ok(_) => Some(true),
+        Err(error) if is_wrong_key(error) => Some(false),
+        Err(_) => None,
+    }
+}
+
 fn reset_mode(file: &mut FileScout) {
-    file.input.content.clear();
-    file.mode = ViewMode::ListView;
+    // The editor may have held a decrypted file.
+    file.input.content.zeroize();
+    file.document = None;
+    file.prompt.clear();
+    file.first_passphrase = None;
+    file.pending = None;
+    // A batch that finished while a prompt was open reports now.
+    file.mode = if file.report.is_empty() {
+        ViewMode::ListView
+    } else {
+        ViewMode::Report
+    };
 }
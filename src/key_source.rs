@@ -0,0 +1,133 @@
+use std::{
+    env, fs,
+    io::{Error, ErrorKind, Result},
+    path::PathBuf,
+};
+
+use zeroize::Zeroizing;
+
+pub const KEY_LEN: usize = 32;
+
+/// Where the secret behind an encryption key comes from.
+pub enum KeySource {
+    /// The key compiled into `constant::KEY`, only kept to open files made
+    /// before passphrases were introduced.
+    Legacy,
+    /// Stretched into a key with Argon2id.
+    Passphrase(Zeroizing<String>),
+    /// A file holding a raw 256-bit key, either as 32 bytes or 64 hex digits.
+    KeyFile(PathBuf),
+    /// An environment variable holding a raw 256-bit key as 64 hex digits.
+    Env(String),
+    /// An identity file with X25519 secret keys in age's format, opening
+    /// age files and files encrypted to recipients.
+    Identity(PathBuf),
+    /// A file listing X25519 public keys to encrypt to.
+    Recipients(PathBuf),
+}
+
+impl KeySource {
+    pub fn passphrase(passphrase: String) -> Self {
+        KeySource::Passphrase(Zeroizing::new(passphrase))
+    }
+
+    /// Reads the raw key for the key file and environment sources. The
+    /// buffers it passes through are wiped once they are dropped.
+    pub fn raw_key(&self) -> Result<Zeroizing<[u8; KEY_LEN]>> {
+        let material = match self {
+            KeySource::KeyFile(path) => Zeroizing::new(fs::read(path)?),
+            KeySource::Env(name) => {
+                let value =
+                    Zeroizing::new(env::var(name).map_err(|_| {
+                        Error::new(ErrorKind::NotFound, format!("{name} is not set"))
+                    })?);
+                Zeroizing::new(value.as_bytes().to_vec())
+            }
+            KeySource::Legacy
+            | KeySource::Passphrase(_)
+            | KeySource::Identity(_)
+            | KeySource::Recipients(_) => {
+                return Err(Error::new(ErrorKind::InvalidInput, "Not a raw key source"))
+            }
+        };
+
+        let mut key = Zeroizing::new([0u8; KEY_LEN]);
+        if material.len() == KEY_LEN && matches!(self, KeySource::KeyFile(_)) {
+            key.copy_from_slice(&material);
+            return Ok(key);
+        }
+        let hex = material.trim_ascii();
+        if hex.len() != KEY_LEN * 2 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Key must be 32 bytes or 64 hex digits",
+            ));
+        }
+        for (byte, pair) in key.iter_mut().zip(hex.chunks(2)) {
+            *byte = (hex_digit(pair[0])? << 4) | hex_digit(pair[1])?;
+        }
+        Ok(key)
+    }
+}
+
+fn hex_digit(digit: u8) -> Result<u8> {
+    match digit {
+        b'0'..=b'9' => Ok(digit - b'0'),
+        b'a'..=b'f' => Ok(digit - b'a' + 10),
+        b'A'..=b'F' => Ok(digit - b'A' + 10),
+        _ => Err(Error::new(ErrorKind::InvalidData, "Key is not valid hex")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    const HEX: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191A1B1C1D1E1F";
+
+    fn key_file(name: &str, content: &[u8]) -> Result<Zeroizing<[u8; KEY_LEN]>> {
+        let path = env::temp_dir().join(format!("filescout-key-{name}-{}", process::id()));
+        fs::write(&path, content).unwrap();
+        let key = KeySource::KeyFile(path.clone()).raw_key();
+        fs::remove_file(&path).unwrap();
+        key
+    }
+
+    fn expected() -> [u8; KEY_LEN] {
+        std::array::from_fn(|i| i as u8)
+    }
+
+    #[test]
+    fn accepts_raw_and_hex_keys() {
+        assert_eq!(*key_file("raw", &expected()).unwrap(), expected());
+        let hex = format!("{HEX}\n");
+        assert_eq!(*key_file("hex", hex.as_bytes()).unwrap(), expected());
+        env::set_var("FILESCOUT_TEST_KEY_OK", format!(" {HEX} "));
+        let key = KeySource::Env(String::from("FILESCOUT_TEST_KEY_OK")).raw_key();
+        assert_eq!(*key.unwrap(), expected());
+    }
+
+    #[test]
+    fn rejects_bad_keys() {
+        let bad_hex = HEX.replace('a', "g");
+        let contents: [&[u8]; 4] = [
+            &[0; KEY_LEN - 1],
+            &[0; KEY_LEN + 1],
+            &HEX.as_bytes()[1..],
+            bad_hex.as_bytes(),
+        ];
+        for (i, content) in contents.into_iter().enumerate() {
+            let error = key_file(&format!("bad{i}"), content).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+        }
+        // Only key files may hold raw bytes.
+        let raw = String::from_utf8(vec![b'a'; KEY_LEN]).unwrap();
+        env::set_var("FILESCOUT_TEST_KEY_BAD", raw);
+        let error = KeySource::Env(String::from("FILESCOUT_TEST_KEY_BAD")).raw_key();
+        assert_eq!(error.unwrap_err().kind(), ErrorKind::InvalidData);
+        let error = KeySource::Env(String::from("FILESCOUT_TEST_KEY_UNSET")).raw_key();
+        assert_eq!(error.unwrap_err().kind(), ErrorKind::NotFound);
+    }
+}
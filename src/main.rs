@@ -1,11 +1,28 @@
+mod age_file;
+mod cli;
+mod config;
 mod constant;
 mod crypto_handler;
 mod explorer;
+mod filter;
+mod finder;
+mod header;
+mod jobs;
+mod key_cache;
 mod key_events;
+mod key_source;
+mod recipient;
+mod search;
+mod sort;
+mod stream;
 mod ui;
+mod walk;
 
+use clap::Parser;
+use cli::Cli;
 use color_eyre::eyre::Result;
 use explorer::FileStruct;
+use filter::Filter;
 use ratatui::DefaultTerminal;
 use std::path::Path;
 use ui::FileScout;
@@ -13,10 +30,21 @@ use ui::FileScout;
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
-    let mut file = FileStruct::default();
+    let cli = Cli::parse();
+    let config = cli.config()?;
+    if let Some(command) = cli.command {
+        command.run(&config)?;
+        return Ok(());
+    }
+
+    let mut file = FileStruct {
+        default_sort: config.listing.sort(),
+        filter: Filter::new(&config.listing)?,
+        ..FileStruct::default()
+    };
     file.present_dir_fn(Path::new("."), None);
     let mut terminal: DefaultTerminal = ratatui::init();
-    let app = FileScout::new(file);
+    let app = FileScout::new(file, config);
     app.run(&mut terminal).await?;
     ratatui::restore();
     Ok(())
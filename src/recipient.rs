@@ -0,0 +1,186 @@
+//! Public-key encryption to X25519 recipients.
+//!
+//! Every file gets a random data key. For each recipient a fresh ephemeral
+//! key pair is made, and the data key is sealed with ChaCha20-Poly1305 under
+//! a key derived with HKDF-SHA256 from the shared secret and both public
+//! keys. Opening a file tries every identity against every wrapped key.
+//!
+//! Keys use age's text encodings, so `age-keygen` can make them: recipients
+//! are `age1...` lines, identities `AGE-SECRET-KEY-1...` lines, and `#`
+//! starts a comment.
+
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+use aes_gcm::aead::{rand_core::RngCore, Aead, KeyInit, OsRng};
+use bech32::{FromBase32, Variant};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::{crypto_handler::CryptoError, header::WrappedKey, key_source::KEY_LEN};
+
+const RECIPIENT_HRP: &str = "age";
+const IDENTITY_HRP: &str = "age-secret-key-";
+/// HKDF info string binding a shared secret to wrapping a data key.
+const WRAP_INFO: &[u8] = b"filescout x25519 wrap";
+
+/// Reads the public keys listed in a recipients file.
+pub fn read_recipients(path: &Path) -> Result<Vec<PublicKey>> {
+    let recipients = key_lines(path)?
+        .iter()
+        .map(|line| decode(line, RECIPIENT_HRP, "recipient").map(PublicKey::from))
+        .collect::<Result<Vec<_>>>()?;
+    if recipients.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("No recipients in {}", path.display()),
+        ));
+    }
+    Ok(recipients)
+}
+
+/// Reads the secret keys in an identity file.
+pub fn read_identities(path: &Path) -> Result<Vec<StaticSecret>> {
+    key_lines(path)?
+        .iter()
+        .map(|line| decode(line, IDENTITY_HRP, "identity").map(StaticSecret::from))
+        .collect()
+}
+
+/// The non-empty, non-comment lines of a key file, wiped once dropped.
+pub fn key_lines(path: &Path) -> Result<Zeroizing<Vec<String>>> {
+    let content = Zeroizing::new(fs::read_to_string(path)?);
+    Ok(Zeroizing::new(
+        content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(String::from)
+            .collect(),
+    ))
+}
+
+/// Seals `data_key` for each of `recipients`.
+pub fn wrap_key(data_key: &[u8; KEY_LEN], recipients: &[PublicKey]) -> Result<Vec<WrappedKey>> {
+    recipients
+        .iter()
+        .map(|recipient| {
+            let secret = EphemeralSecret::random_from_rng(OsRng);
+            let ephemeral = PublicKey::from(&secret);
+            let shared = secret.diffie_hellman(recipient);
+            if !shared.was_contributory() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Recipient key is not a valid public key",
+                ));
+            }
+            let key = wrapping_cipher(shared.as_bytes(), &ephemeral, recipient)?
+                .encrypt(&[0u8; 12].into(), data_key.as_slice())
+                .map_err(|_| Error::other("Encryption failed"))?;
+            Ok(WrappedKey {
+                ephemeral: ephemeral.to_bytes(),
+                key,
+            })
+        })
+        .collect()
+}
+
+/// Recovers the data key with the first identity that one of `wrapped` was
+/// sealed for.
+pub fn unwrap_key(
+    wrapped: &[WrappedKey],
+    identities: &[StaticSecret],
+) -> Result<Zeroizing<[u8; KEY_LEN]>> {
+    for identity in identities {
+        let public = PublicKey::from(identity);
+        for entry in wrapped {
+            let ephemeral = PublicKey::from(entry.ephemeral);
+            let shared = identity.diffie_hellman(&ephemeral);
+            if !shared.was_contributory() {
+                continue;
+            }
+            let cipher = wrapping_cipher(shared.as_bytes(), &ephemeral, &public)?;
+            if let Ok(plain) = cipher.decrypt(&[0u8; 12].into(), entry.key.as_slice()) {
+                let plain = Zeroizing::new(plain);
+                if plain.len() == KEY_LEN {
+                    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+                    key.copy_from_slice(&plain);
+                    return Ok(key);
+                }
+            }
+        }
+    }
+    Err(CryptoError::NoMatchingIdentity.into())
+}
+
+/// Makes a fresh random data key.
+pub fn data_key() -> Zeroizing<[u8; KEY_LEN]> {
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    OsRng.fill_bytes(key.as_mut());
+    key
+}
+
+/// Every wrapping key is used exactly once, so a zero nonce is fine.
+fn wrapping_cipher(
+    shared: &[u8; 32],
+    ephemeral: &PublicKey,
+    recipient: &PublicKey,
+) -> Result<ChaCha20Poly1305> {
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(ephemeral.as_bytes());
+    salt[32..].copy_from_slice(recipient.as_bytes());
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(WRAP_INFO, key.as_mut())
+        .map_err(|_| Error::other("Key derivation failed"))?;
+    Ok(ChaCha20Poly1305::new(key.as_ref().into()))
+}
+
+/// Decodes a bech32 key with the human readable part `hrp`. The line isn't
+/// echoed in errors, as it might hold a secret key.
+fn decode(line: &str, hrp: &str, what: &str) -> Result<[u8; 32]> {
+    let invalid = || Error::new(ErrorKind::InvalidData, format!("Not a valid {what} key"));
+    let (found, data, variant) = bech32::decode(line).map_err(|_| invalid())?;
+    if found != hrp || variant != Variant::Bech32 {
+        return Err(invalid());
+    }
+    let bytes = Zeroizing::new(Vec::<u8>::from_base32(&data).map_err(|_| invalid())?);
+    bytes.as_slice().try_into().map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unwraps_with_a_matching_identity() {
+        let (ours, theirs) = (
+            StaticSecret::random_from_rng(OsRng),
+            StaticSecret::random_from_rng(OsRng),
+        );
+        let key = data_key();
+        let wrapped = wrap_key(&key, &[PublicKey::from(&theirs), PublicKey::from(&ours)]).unwrap();
+        assert_eq!(wrapped.len(), 2);
+        assert_eq!(*unwrap_key(&wrapped, &[ours]).unwrap(), *key);
+    }
+
+    #[test]
+    fn refuses_other_identities() {
+        let (ours, theirs) = (
+            StaticSecret::random_from_rng(OsRng),
+            StaticSecret::random_from_rng(OsRng),
+        );
+        let wrapped = wrap_key(&data_key(), &[PublicKey::from(&theirs)]).unwrap();
+        let error = unwrap_key(&wrapped, &[ours]).unwrap_err();
+        assert!(matches!(
+            CryptoError::of(&error),
+            Some(CryptoError::NoMatchingIdentity)
+        ));
+    }
+}
@@ -0,0 +1,218 @@
+//! Searches the contents of files below a directory for a string or regular
+//! expression. Files are read in the background, so results show up while
+//! the search is still going.
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufRead, BufReader, Error, ErrorKind, Result},
+    ops::{ControlFlow, Range},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use ratatui::widgets::ListState;
+use regex::{Regex, RegexBuilder};
+use tokio::sync::mpsc::Sender;
+
+use crate::{crypto_handler::detect_format, filter::Filter, ui::Message, walk::Walk};
+
+/// The search stops after this many matches.
+pub const MAX_HITS: usize = 1000;
+/// Lines shown before and after a match.
+pub const CONTEXT: usize = 2;
+
+/// A line that matches.
+pub struct Hit {
+    pub path: PathBuf,
+    /// Counted from 1.
+    pub line: usize,
+    pub text: String,
+    /// Byte ranges of `text` that match.
+    pub ranges: Vec<Range<usize>>,
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+pub struct Search {
+    /// The run the hits are from, if there has been one.
+    walk: Option<Walk>,
+    pub root: PathBuf,
+    /// What is typed, run once `Enter` is pressed.
+    pub pattern: String,
+    /// Whether `pattern` is a regular expression rather than plain text.
+    pub regex: bool,
+    pub state: ListState,
+    /// Pattern and kind of the run the hits are from.
+    ran: Option<(String, bool)>,
+    hits: Vec<Hit>,
+    /// How many files the run has looked at.
+    pub searched: usize,
+    /// Whether the run is still going.
+    pub scanning: bool,
+}
+
+impl Search {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            walk: None,
+            root,
+            pattern: String::new(),
+            regex: false,
+            state: ListState::default(),
+            ran: None,
+            hits: Vec::new(),
+            searched: 0,
+            scanning: false,
+        }
+    }
+
+    /// Starts searching for `pattern`, stopping the run before. Batches of
+    /// hits are posted as `Message::Hits`.
+    pub fn start(&mut self, filter: &Filter, tx: Sender<Message>) -> Result<()> {
+        let regex = self.compile()?;
+        self.ran = Some((self.pattern.clone(), self.regex));
+        self.hits.clear();
+        self.searched = 0;
+        self.scanning = true;
+        self.state.select(None);
+
+        let mut walker = filter.walker(&self.root);
+        walker.sort_by_file_name(|a, b| a.cmp(b));
+        let mut total = 0;
+        // Replacing the run before stops it.
+        self.walk = Some(Walk::start(
+            walker,
+            tx,
+            move |entry, (hits, searched): &mut (Vec<Hit>, usize), stop| {
+                if !entry.file_type().is_some_and(|kind| kind.is_file()) {
+                    return ControlFlow::Continue(());
+                }
+                // Encrypted files only hold ciphertext, and age files can
+                // look like text.
+                if detect_format(entry.path()).is_ok() {
+                    return ControlFlow::Continue(());
+                }
+                *searched += 1;
+                let mut found = search_file(entry.path(), &regex, stop).unwrap_or_default();
+                found.truncate(MAX_HITS - total);
+                total += found.len();
+                hits.append(&mut found);
+                if total >= MAX_HITS {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            },
+            |id, (hits, searched), done| Message::Hits {
+                id,
+                hits,
+                searched,
+                done,
+            },
+        ));
+        Ok(())
+    }
+
+    /// Plain text is matched literally. Either ignores case unless the
+    /// pattern has an upper-case letter.
+    fn compile(&self) -> Result<Regex> {
+        let pattern = if self.regex {
+            self.pattern.clone()
+        } else {
+            regex::escape(&self.pattern)
+        };
+        RegexBuilder::new(&pattern)
+            .case_insensitive(!self.pattern.chars().any(char::is_uppercase))
+            .build()
+            .map_err(|error| {
+                // The full message points at the error over several lines.
+                let error = error.to_string();
+                let reason = error.lines().last().unwrap_or_default();
+                let reason = reason.trim_start_matches("error: ");
+                Error::new(ErrorKind::InvalidInput, format!("Bad pattern: {reason}"))
+            })
+    }
+
+    /// Tells the batches of a run apart from those of an earlier one.
+    pub fn id(&self) -> Option<usize> {
+        self.walk.as_ref().map(|walk| walk.id)
+    }
+
+    /// Takes in a batch from the run.
+    pub fn add(&mut self, hits: Vec<Hit>, searched: usize, done: bool) {
+        self.hits.extend(hits);
+        self.searched += searched;
+        self.scanning = !done;
+        if self.state.selected().is_none() && !self.hits.is_empty() {
+            self.state.select(Some(0));
+        }
+    }
+
+    /// Whether the hits are for what is typed now.
+    pub fn is_current(&self) -> bool {
+        self.ran
+            .as_ref()
+            .is_some_and(|(pattern, regex)| *pattern == self.pattern && *regex == self.regex)
+    }
+
+    pub fn hits(&self) -> &[Hit] {
+        &self.hits
+    }
+
+    pub fn selected(&self) -> Option<&Hit> {
+        self.hits.get(self.state.selected()?)
+    }
+
+    /// Whether the run stopped at `MAX_HITS` rather than at the last file.
+    pub fn is_truncated(&self) -> bool {
+        self.hits.len() >= MAX_HITS
+    }
+}
+
+/// The lines of `path` that match, with their context. Binary files, told
+/// apart by a NUL byte near the start, have none.
+fn search_file(path: &Path, regex: &Regex, stop: &AtomicBool) -> Result<Vec<Hit>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if reader.fill_buf()?.contains(&0) {
+        return Ok(Vec::new());
+    }
+    let mut hits: Vec<Hit> = Vec::new();
+    let mut before = VecDeque::with_capacity(CONTEXT);
+    // Hits from `open` on still miss lines after them.
+    let mut open = 0;
+    let mut buf = Vec::new();
+    let mut number = 0;
+    while reader.read_until(b'\n', &mut buf)? > 0 {
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+        number += 1;
+        let line = String::from_utf8_lossy(&buf)
+            .trim_end_matches(['\n', '\r'])
+            .to_string();
+        buf.clear();
+        for hit in &mut hits[open..] {
+            hit.after.push(line.clone());
+        }
+        while hits.get(open).is_some_and(|hit| hit.after.len() == CONTEXT) {
+            open += 1;
+        }
+        let ranges: Vec<Range<usize>> = regex.find_iter(&line).map(|found| found.range()).collect();
+        if !ranges.is_empty() {
+            hits.push(Hit {
+                path: path.to_path_buf(),
+                line: number,
+                text: line.clone(),
+                ranges,
+                before: before.iter().cloned().collect(),
+                after: Vec::new(),
+            });
+        }
+        if before.len() == CONTEXT {
+            before.pop_front();
+        }
+        before.push_back(line);
+    }
+    Ok(hits)
+}
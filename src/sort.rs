@@ -0,0 +1,251 @@
+//! Orders directory listings. Directories always come first, then each group
+//! is sorted by the chosen key, ties broken by name.
+
+use std::{
+    cmp::Ordering,
+    fmt::{self, Display, Formatter},
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::Deserialize;
+
+#[derive(Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SortKey {
+    /// By name, ignoring case and comparing runs of digits as numbers, so
+    /// `file2` comes before `file10`.
+    #[default]
+    Natural,
+    /// By name, ignoring case.
+    Name,
+    Size,
+    Modified,
+    Extension,
+    /// Regular files, then symlinks, then anything else.
+    Type,
+}
+
+impl SortKey {
+    const ALL: [SortKey; 6] = [
+        SortKey::Natural,
+        SortKey::Name,
+        SortKey::Size,
+        SortKey::Modified,
+        SortKey::Extension,
+        SortKey::Type,
+    ];
+
+    /// The key after this one, wrapping around.
+    pub fn next(self) -> Self {
+        let index = Self::ALL.iter().position(|key| *key == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+}
+
+impl Display for SortKey {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let name = match self {
+            SortKey::Natural => "natural name",
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "modification time",
+            SortKey::Extension => "extension",
+            SortKey::Type => "type",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct Sort {
+    pub key: SortKey,
+    pub descending: bool,
+}
+
+impl Display for Sort {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let order = if self.descending {
+            "descending"
+        } else {
+            "ascending"
+        };
+        write!(f, "{}, {order}", self.key)
+    }
+}
+
+impl Sort {
+    /// Sorts `entries` in place, directories first.
+    pub fn apply(self, entries: &mut [PathBuf]) {
+        // Metadata is read once per entry, not once per comparison.
+        let mut keyed: Vec<(Entry, PathBuf)> = entries
+            .iter()
+            .map(|path| (Entry::new(path), path.to_path_buf()))
+            .collect();
+        keyed.sort_by(|(a, _), (b, _)| {
+            let order = self
+                .compare(a, b)
+                .then_with(|| natural_cmp(&a.name, &b.name));
+            let order = if self.descending {
+                order.reverse()
+            } else {
+                order
+            };
+            b.is_dir.cmp(&a.is_dir).then(order)
+        });
+        for (entry, (_, path)) in entries.iter_mut().zip(keyed) {
+            *entry = path;
+        }
+    }
+
+    fn compare(self, a: &Entry, b: &Entry) -> Ordering {
+        match self.key {
+            SortKey::Natural => Ordering::Equal,
+            SortKey::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+            SortKey::Extension => a.extension.cmp(&b.extension),
+            SortKey::Type => a.kind.cmp(&b.kind),
+        }
+    }
+}
+
+/// What a listing is sorted on.
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+    extension: String,
+    /// 0 for regular files, 1 for symlinks, 2 for anything else.
+    kind: u8,
+}
+
+impl Entry {
+    fn new(path: &Path) -> Self {
+        let link = fs::symlink_metadata(path).ok();
+        let metadata = fs::metadata(path).ok().or_else(|| link.clone());
+        let kind = match &link {
+            Some(link) if link.is_symlink() => 1,
+            Some(link) if link.is_file() => 0,
+            _ => 2,
+        };
+        Self {
+            name: path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            is_dir: path.is_dir(),
+            size: metadata.as_ref().map_or(0, |metadata| metadata.len()),
+            modified: metadata.and_then(|metadata| metadata.modified().ok()),
+            extension: path
+                .extension()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_lowercase(),
+            kind,
+        }
+    }
+}
+
+/// Compares names ignoring case, with runs of digits compared by value.
+/// Names that only differ in case or leading zeros fall back to comparing
+/// them as they are, so the order is total.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut left, mut right) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        match (left.peek().copied(), right.peek().copied()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(l), Some(r)) if l.is_ascii_digit() && r.is_ascii_digit() => {
+                let l = digits(&mut left);
+                let r = digits(&mut right);
+                let (l, r) = (l.trim_start_matches('0'), r.trim_start_matches('0'));
+                let order = l.len().cmp(&r.len()).then_with(|| l.cmp(r));
+                if order != Ordering::Equal {
+                    return order;
+                }
+            }
+            (Some(l), Some(r)) => {
+                let order = l.to_lowercase().cmp(r.to_lowercase());
+                if order != Ordering::Equal {
+                    return order;
+                }
+                left.next();
+                right.next();
+            }
+        }
+    }
+}
+
+fn digits(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut run = String::new();
+    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+        run.push(digit);
+    }
+    run
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn names(entries: &[PathBuf]) -> Vec<&str> {
+        entries
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn compares_numbers_by_value() {
+        assert_eq!(natural_cmp("file2", "file10"), Ordering::Less);
+        assert_eq!(natural_cmp("file10", "file2"), Ordering::Greater);
+        assert_eq!(natural_cmp("v1.9", "v1.10"), Ordering::Less);
+    }
+
+    #[test]
+    fn ignores_case() {
+        assert_eq!(natural_cmp("apple", "Banana"), Ordering::Less);
+        assert_eq!(natural_cmp("File3", "file10"), Ordering::Less);
+    }
+
+    #[test]
+    fn orders_ties_totally() {
+        // Leading zeros and case only decide when nothing else does.
+        assert_eq!(natural_cmp("file02", "file2"), Ordering::Less);
+        assert_eq!(natural_cmp("file2", "file02"), Ordering::Greater);
+        assert_eq!(natural_cmp("File", "file"), Ordering::Less);
+        assert_eq!(natural_cmp("file", "file"), Ordering::Equal);
+    }
+
+    #[test]
+    fn sorts_directories_first_in_both_directions() {
+        let dir = env::temp_dir().join(format!("filescout-sort-{}", process::id()));
+        fs::create_dir_all(dir.join("dir10")).unwrap();
+        fs::create_dir_all(dir.join("dir2")).unwrap();
+        for name in ["file10", "File2", "file1"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let mut entries: Vec<PathBuf> = ["file10", "dir10", "File2", "dir2", "file1"]
+            .iter()
+            .map(|name| dir.join(name))
+            .collect();
+
+        let mut sort = Sort::default();
+        sort.apply(&mut entries);
+        let ascending = names(&entries);
+        assert_eq!(ascending, ["dir2", "dir10", "file1", "File2", "file10"]);
+
+        sort.descending = true;
+        sort.apply(&mut entries);
+        let descending = names(&entries);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(descending, ["dir10", "dir2", "file10", "File2", "file1"]);
+    }
+}
@@ -0,0 +1,416 @@
+//! Chunked encryption following the STREAM construction.
+//!
+//! The plaintext is split into segments of `segment_size` bytes, each sealed
+//! on its own with a nonce made of the per-file prefix, a segment counter and
+//! a last-segment flag. Memory use stays at one segment no matter how big the
+//! file is, and dropping, reordering or truncating segments makes
+//! authentication fail.
+
+use std::io::{Error, Read, Result, Write};
+
+use aes_gcm::{
+    aead::{
+        self,
+        stream::{DecryptorBE32, EncryptorBE32},
+        Payload,
+    },
+    Aes256Gcm,
+};
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
+
+use crate::crypto_handler::CryptoError;
+
+pub const SEGMENT_SIZE: usize = 64 * 1024;
+pub const TAG_LEN: usize = 16;
+/// Nonce prefix length for a 96-bit nonce once STREAM takes its 5 bytes.
+pub const NONCE_PREFIX_LEN: usize = 7;
+/// Nonce prefix length for XChaCha20's 192-bit nonce.
+pub const XNONCE_PREFIX_LEN: usize = 19;
+
+fn auth_error() -> Error {
+    CryptoError::AuthenticationFailed.into()
+}
+
+/// A keyed AEAD to seal segments with. All of them use 16-byte tags. AES
+/// keeps its expanded key schedule inline, so it is boxed.
+pub enum SegmentCipher {
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(ChaCha20Poly1305),
+    XChaCha20Poly1305(XChaCha20Poly1305),
+}
+
+/// Calls the same method on whichever STREAM state `$value` holds.
+macro_rules! dispatch {
+    ($kind:ident, $value:expr, $inner:ident => $call:expr) => {
+        match $value {
+            $kind::Aes256Gcm($inner) => $call,
+            $kind::ChaCha20Poly1305($inner) => $call,
+            $kind::XChaCha20Poly1305($inner) => $call,
+        }
+    };
+}
+
+enum Encryptor {
+    Aes256Gcm(Box<EncryptorBE32<Aes256Gcm>>),
+    ChaCha20Poly1305(EncryptorBE32<ChaCha20Poly1305>),
+    XChaCha20Poly1305(EncryptorBE32<XChaCha20Poly1305>),
+}
+
+impl Encryptor {
+    /// `nonce` must be the prefix length for the cipher. Nothing checks it
+    /// here; readers check the header's in `crypto_handler::open_with`.
+    fn new(cipher: SegmentCipher, nonce: &[u8]) -> Self {
+        match cipher {
+            SegmentCipher::Aes256Gcm(cipher) => {
+                Encryptor::Aes256Gcm(Box::new(EncryptorBE32::from_aead(*cipher, nonce.into())))
+            }
+            SegmentCipher::ChaCha20Poly1305(cipher) => {
+                Encryptor::ChaCha20Poly1305(EncryptorBE32::from_aead(cipher, nonce.into()))
+            }
+            SegmentCipher::XChaCha20Poly1305(cipher) => {
+                Encryptor::XChaCha20Poly1305(EncryptorBE32::from_aead(cipher, nonce.into()))
+            }
+        }
+    }
+
+    fn encrypt_next(&mut self, payload: Payload) -> aead::Result<Vec<u8>> {
+        dispatch!(Encryptor, self, inner => inner.encrypt_next(payload))
+    }
+
+    fn encrypt_last(self, payload: Payload) -> aead::Result<Vec<u8>> {
+        dispatch!(Encryptor, self, inner => inner.encrypt_last(payload))
+    }
+}
+
+enum Decryptor {
+    Aes256Gcm(Box<DecryptorBE32<Aes256Gcm>>),
+    ChaCha20Poly1305(DecryptorBE32<ChaCha20Poly1305>),
+    XChaCha20Poly1305(DecryptorBE32<XChaCha20Poly1305>),
+}
+
+impl Decryptor {
+    fn new(cipher: SegmentCipher, nonce: &[u8]) -> Self {
+        match cipher {
+            SegmentCipher::Aes256Gcm(cipher) => {
+                Decryptor::Aes256Gcm(Box::new(DecryptorBE32::from_aead(*cipher, nonce.into())))
+            }
+            SegmentCipher::ChaCha20Poly1305(cipher) => {
+                Decryptor::ChaCha20Poly1305(DecryptorBE32::from_aead(cipher, nonce.into()))
+            }
+            SegmentCipher::XChaCha20Poly1305(cipher) => {
+                Decryptor::XChaCha20Poly1305(DecryptorBE32::from_aead(cipher, nonce.into()))
+            }
+        }
+    }
+
+    fn decrypt_next(&mut self, payload: Payload) -> aead::Result<Vec<u8>> {
+        dispatch!(Decryptor, self, inner => inner.decrypt_next(payload))
+    }
+
+    fn decrypt_last(self, payload: Payload) -> aead::Result<Vec<u8>> {
+        dispatch!(Decryptor, self, inner => inner.decrypt_last(payload))
+    }
+}
+
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    encryptor: Encryptor,
+    aad: Vec<u8>,
+    buf: Vec<u8>,
+    segment_size: usize,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(
+        inner: W,
+        cipher: SegmentCipher,
+        nonce: &[u8],
+        aad: Vec<u8>,
+        segment_size: usize,
+    ) -> Self {
+        Self {
+            inner,
+            encryptor: Encryptor::new(cipher, nonce),
+            aad,
+            buf: Vec::with_capacity(segment_size),
+            segment_size,
+        }
+    }
+
+    /// Seals whatever is buffered as the final segment and hands back the
+    /// underlying writer. Every stream has a final segment, even if empty.
+    pub fn finish(mut self) -> Result<W> {
+        let payload = Payload {
+            msg: &self.buf,
+            aad: &self.aad,
+        };
+        let segment = self
+            .encryptor
+            .encrypt_last(payload)
+            .map_err(|_| Error::other("Encryption failed"))?;
+        self.inner.write_all(&segment)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, data: &[u8]) -> Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        // A full buffer is only sealed once more data shows up, since until
+        // then it might still turn out to be the last segment.
+        if self.buf.len() == self.segment_size {
+            let payload = Payload {
+                msg: &self.buf,
+                aad: &self.aad,
+            };
+            let segment = self
+                .encryptor
+                .encrypt_next(payload)
+                .map_err(|_| Error::other("Encryption failed"))?;
+            self.inner.write_all(&segment)?;
+            self.buf.clear();
+        }
+        let len = data.len().min(self.segment_size - self.buf.len());
+        self.buf.extend_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    decryptor: Option<Decryptor>,
+    aad: Vec<u8>,
+    segment_size: usize,
+    /// Byte read past the current segment to find out whether it was the last.
+    lookahead: Option<u8>,
+    plaintext: Vec<u8>,
+    pos: usize,
+    total: u64,
+    /// Plaintext length recorded in the header, when it is known.
+    expected: Option<u64>,
+}
+
+impl<R: Read> DecryptReader<R> {
+    pub fn new(
+        inner: R,
+        cipher: SegmentCipher,
+        nonce: &[u8],
+        aad: Vec<u8>,
+        segment_size: usize,
+        expected: Option<u64>,
+    ) -> Self {
+        Self {
+            inner,
+            decryptor: Some(Decryptor::new(cipher, nonce)),
+            aad,
+            segment_size,
+            lookahead: None,
+            plaintext: Vec::new(),
+            pos: 0,
+            total: 0,
+            expected,
+        }
+    }
+
+    fn next_segment(&mut self) -> Result<()> {
+        let capacity = self.segment_size + TAG_LEN;
+        let mut segment = Vec::with_capacity(capacity + 1);
+        segment.extend(self.lookahead.take());
+        (&mut self.inner)
+            .take((capacity + 1 - segment.len()) as u64)
+            .read_to_end(&mut segment)?;
+        if segment.len() < TAG_LEN {
+            return Err(CryptoError::Truncated.into());
+        }
+
+        if segment.len() > capacity {
+            self.lookahead = segment.pop();
+            let payload = Payload {
+                msg: &segment,
+                aad: &self.aad,
+            };
+            let decryptor = self.decryptor.as_mut().ok_or_else(auth_error)?;
+            self.plaintext = decryptor.decrypt_next(payload).map_err(|_| auth_error())?;
+        } else {
+            let payload = Payload {
+                msg: &segment,
+                aad: &self.aad,
+            };
+            let decryptor = self.decryptor.take().ok_or_else(auth_error)?;
+            self.plaintext = decryptor.decrypt_last(payload).map_err(|_| auth_error())?;
+        }
+        self.pos = 0;
+        self.total += self.plaintext.len() as u64;
+        if let Some(expected) = self.expected {
+            if self.total > expected || (self.decryptor.is_none() && self.total != expected) {
+                return Err(CryptoError::Malformed(String::from("file size mismatch")).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize> {
+        while self.pos == self.plaintext.len() {
+            if self.decryptor.is_none() {
+                return Ok(0);
+            }
+            self.next_segment()?;
+        }
+        let len = out.len().min(self.plaintext.len() - self.pos);
+        out[..len].copy_from_slice(&self.plaintext[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aes_gcm::KeyInit;
+    use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
+
+    use super::*;
+
+    /// Small segments keep the tampering tests short.
+    const SMALL: usize = 16;
+    const AAD: &[u8] = b"header";
+
+    fn aes() -> SegmentCipher {
+        SegmentCipher::Aes256Gcm(Box::new(Aes256Gcm::new(&[1; 32].into())))
+    }
+
+    fn encrypt(cipher: SegmentCipher, nonce: &[u8], data: &[u8], segment_size: usize) -> Vec<u8> {
+        let mut writer = EncryptWriter::new(Vec::new(), cipher, nonce, AAD.to_vec(), segment_size);
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(
+        cipher: SegmentCipher,
+        nonce: &[u8],
+        data: &[u8],
+        segment_size: usize,
+    ) -> Result<Vec<u8>> {
+        let mut reader = DecryptReader::new(data, cipher, nonce, AAD.to_vec(), segment_size, None);
+        let mut plaintext = Vec::new();
+        reader.read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    fn is_auth_failure(result: Result<Vec<u8>>) -> bool {
+        result.err().is_some_and(|error| {
+            error
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<CryptoError>())
+                .is_some_and(|error| {
+                    matches!(
+                        error,
+                        CryptoError::AuthenticationFailed | CryptoError::Truncated
+                    )
+                })
+        })
+    }
+
+    /// Three full segments and a short final one.
+    fn sealed() -> (Vec<u8>, Vec<u8>) {
+        let data: Vec<u8> = (0..SMALL as u8 * 3 + 5).collect();
+        let sealed = encrypt(aes(), &[0; NONCE_PREFIX_LEN], &data, SMALL);
+        (data, sealed)
+    }
+
+    #[test]
+    fn round_trips() {
+        let (data, sealed) = sealed();
+        let opened = decrypt(aes(), &[0; NONCE_PREFIX_LEN], &sealed, SMALL).unwrap();
+        assert_eq!(opened, data);
+    }
+
+    #[test]
+    fn round_trips_each_cipher_at_segment_boundaries() {
+        let ciphers: [(fn() -> SegmentCipher, usize); 3] = [
+            (aes, NONCE_PREFIX_LEN),
+            (
+                || SegmentCipher::ChaCha20Poly1305(ChaCha20Poly1305::new(&[2; 32].into())),
+                NONCE_PREFIX_LEN,
+            ),
+            (
+                || SegmentCipher::XChaCha20Poly1305(XChaCha20Poly1305::new(&[3; 32].into())),
+                XNONCE_PREFIX_LEN,
+            ),
+        ];
+        for (cipher, nonce_len) in ciphers {
+            let nonce = vec![9; nonce_len];
+            for len in [0, SEGMENT_SIZE - 1, SEGMENT_SIZE, SEGMENT_SIZE + 1] {
+                let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+                let sealed = encrypt(cipher(), &nonce, &data, SEGMENT_SIZE);
+                let segments = len.div_ceil(SEGMENT_SIZE).max(1);
+                assert_eq!(sealed.len(), len + segments * TAG_LEN, "{len} bytes");
+                let opened = decrypt(cipher(), &nonce, &sealed, SEGMENT_SIZE).unwrap();
+                assert_eq!(opened, data, "{len} bytes");
+            }
+        }
+    }
+
+    #[test]
+    fn detects_truncation() {
+        let (_, sealed) = sealed();
+        for len in [0, 1, sealed.len() - 1] {
+            let opened = decrypt(aes(), &[0; NONCE_PREFIX_LEN], &sealed[..len], SMALL);
+            assert!(is_auth_failure(opened), "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn detects_dropped_final_segment() {
+        let (_, sealed) = sealed();
+        let full = SMALL + TAG_LEN;
+        let opened = decrypt(aes(), &[0; NONCE_PREFIX_LEN], &sealed[..full * 3], SMALL);
+        assert!(is_auth_failure(opened));
+    }
+
+    #[test]
+    fn detects_dropped_segment() {
+        let (_, sealed) = sealed();
+        let full = SMALL + TAG_LEN;
+        let mut tampered = sealed[..full].to_vec();
+        tampered.extend_from_slice(&sealed[full * 2..]);
+        let opened = decrypt(aes(), &[0; NONCE_PREFIX_LEN], &tampered, SMALL);
+        assert!(is_auth_failure(opened));
+    }
+
+    #[test]
+    fn detects_reordering() {
+        let (_, sealed) = sealed();
+        let full = SMALL + TAG_LEN;
+        let mut tampered = sealed[full..full * 2].to_vec();
+        tampered.extend_from_slice(&sealed[..full]);
+        tampered.extend_from_slice(&sealed[full * 2..]);
+        let opened = decrypt(aes(), &[0; NONCE_PREFIX_LEN], &tampered, SMALL);
+        assert!(is_auth_failure(opened));
+    }
+
+    #[test]
+    fn detects_other_aad() {
+        let (_, sealed) = sealed();
+        let mut reader = DecryptReader::new(
+            sealed.as_slice(),
+            aes(),
+            &[0; NONCE_PREFIX_LEN],
+            b"other header".to_vec(),
+            SMALL,
+            None,
+        );
+        let mut plaintext = Vec::new();
+        assert!(is_auth_failure(
+            reader.read_to_end(&mut plaintext).map(|_| plaintext)
+        ));
+    }
+}
@@ -1,5 +1,6 @@
 use std::{
     io::Result,
+    path::PathBuf,
     sync::{Arc, Mutex},
 };
 
@@ -8,14 +9,30 @@ use ratatui::{
     buffer::Buffer,
     layout::{Alignment, Constraint, Flex, Layout, Margin, Position, Rect},
     style::{Style, Stylize},
-    text::{Line, Text},
-    widgets::{Block, Borders, Clear as Clean, List, ListItem, Paragraph, StatefulWidget, Widget},
+    text::{Line, Span, Text},
+    widgets::{
+        Block, Borders, Clear as Clean, LineGauge, List, ListItem, Paragraph, StatefulWidget,
+        Widget,
+    },
     DefaultTerminal, Frame,
 };
 use tokio::sync::mpsc;
 use tokio_stream::StreamExt;
+use zeroize::Zeroizing;
+
+use crate::{
+    config::Config,
+    constant::COLORS,
+    crypto_handler::{EncryptedDocument, Format},
+    explorer::FileStruct,
+    finder::Finder,
+    jobs::{JobEvent, JobManager},
+    key_cache::KeyCache,
+    search::{Hit, Search, CONTEXT},
+};
 
-use crate::{constant::COLORS, explorer::FileStruct};
+/// Most jobs the jobs panel shows at once.
+const MAX_JOB_ROWS: usize = 5;
 
 #[derive(PartialEq)]
 pub enum ViewMode {
@@ -24,6 +41,93 @@ pub enum ViewMode {
     Rename,
     Create,
     FileEdit,
+    Passphrase,
+    Confirm,
+    /// Asks where a decrypted file should go.
+    SaveAs,
+    /// Results of a batch, shown until dismissed.
+    Report,
+    /// Typing a fuzzy filter for the current directory.
+    Filter,
+    /// Finding a file anywhere below the current directory.
+    Finder,
+    /// Searching the contents of files below the current directory.
+    Search,
+}
+
+/// What background tasks post to the UI loop.
+pub enum Message {
+    /// Shared state changed, redraw.
+    Refresh,
+    Job(JobEvent),
+    /// A passphrase opened a file and can be reused for the session. Both
+    /// this and `Forget` carry the key cache generation the job started in.
+    Unlocked(Zeroizing<String>, u64),
+    /// A passphrase didn't open a file, so ask again next time.
+    Forget(u64),
+    /// A file opened for editing, with its text.
+    Document(EncryptedDocument, String),
+    /// How each file of a batch went, shown in a popup.
+    Report(Vec<String>),
+    /// A prompt for an action a job has worked out.
+    Confirm(String, CryptoAction),
+    /// Paths the finder with `id` walked past, and whether it is done.
+    Found {
+        id: usize,
+        paths: Vec<PathBuf>,
+        done: bool,
+    },
+    /// Matching lines the search run `id` found, after looking at
+    /// `searched` more files.
+    Hits {
+        id: usize,
+        hits: Vec<Hit>,
+        searched: usize,
+        done: bool,
+    },
+}
+
+/// Encryption work waiting on the passphrase prompt.
+pub enum CryptoAction {
+    Encrypt(PathBuf),
+    /// Encrypt a file into the age format.
+    AgeEncrypt(PathBuf),
+    Decrypt {
+        path: PathBuf,
+        output: PathBuf,
+        /// The user agreed to replace an existing `output`.
+        overwrite: bool,
+        format: Format,
+    },
+    /// Encrypt, verify the result, then wipe and remove the original.
+    EncryptAndRemove(PathBuf),
+    /// Encrypt each of the marked entries.
+    EncryptBatch(Vec<PathBuf>),
+    /// Decrypt each of the marked files, with the passphrase given for those
+    /// that need one.
+    DecryptBatch(Vec<PathBuf>),
+    /// Decrypt a file under the name sealed inside it, which isn't known yet.
+    DecryptSealed(PathBuf),
+    /// Decrypt a text file into the editor.
+    Edit(PathBuf),
+    /// Decrypt the start of a file into the preview pane.
+    Preview {
+        path: PathBuf,
+        format: Format,
+    },
+    /// Authenticate an encrypted file without writing its plaintext.
+    Verify {
+        path: PathBuf,
+        format: Format,
+    },
+    /// Re-encrypt FileScout files under a new key, replacing each in place.
+    Rekey {
+        targets: Vec<PathBuf>,
+        /// Some of `targets` were encrypted with a passphrase, which has to
+        /// be asked for before the new one.
+        needs_old: bool,
+        old: Option<Zeroizing<String>>,
+    },
 }
 
 #[derive(Default)]
@@ -40,11 +144,28 @@ pub struct FileScout {
     pub text_scroll_x: usize,
     pub color_index: usize,
     pub mode: ViewMode,
+    pub pending: Option<CryptoAction>,
+    /// First entry of a new passphrase, waiting to be repeated.
+    pub first_passphrase: Option<Zeroizing<String>>,
+    /// Set while the editor holds a decrypted file, which is saved encrypted.
+    pub document: Option<EncryptedDocument>,
+    /// Passphrase last typed, reused until the session locks.
+    pub keys: KeyCache,
+    /// Lines of the batch report, waiting to be shown or on screen.
+    pub report: Vec<String>,
+    pub finder: Option<Finder>,
+    /// The last content search, kept to go back to its results.
+    pub search: Option<Search>,
+    /// Question shown by the confirmation popup.
+    pub prompt: String,
+    pub status: Option<String>,
+    pub jobs: JobManager,
+    pub config: Config,
     pub exit: bool,
 }
 
 impl FileScout {
-    pub fn new(files: FileStruct) -> Self {
+    pub fn new(files: FileStruct, config: Config) -> Self {
         Self {
             files: Arc::new(Mutex::new(files)),
             mode: ViewMode::ListView,
@@ -52,15 +173,27 @@ impl FileScout {
             text_scroll_y: 0,
             text_scroll_x: 0,
             color_index: 0,
+            pending: None,
+            first_passphrase: None,
+            document: None,
+            keys: KeyCache::new(config.encryption.lock_timeout()),
+            report: Vec::new(),
+            finder: None,
+            search: None,
+            prompt: String::new(),
+            status: None,
+            jobs: JobManager::default(),
+            config,
             exit: false,
         }
     }
 
     pub async fn run(mut self, terminal: &mut DefaultTerminal) -> Result<()> {
-        use crate::key_events::handle_events;
+        use crate::key_events::{handle_events, open_editor, unlock_session};
         let mut reader = EventStream::new();
-        let (tx, mut rx) = mpsc::channel::<String>(1);
+        let (tx, mut rx) = mpsc::channel::<Message>(64);
         while !self.exit {
+            let idle = self.keys.remaining();
             terminal.draw(|frame| {
                 if self.mode == ViewMode::FileEdit {
                     self.handle_curson(frame);
@@ -71,13 +204,71 @@ impl FileScout {
                 Some(Ok(event)) = reader.next() => {
                     match event {
                         Event::Key(KeyEvent { code, modifiers,kind: KeyEventKind::Press, .. }) => {
+                            self.keys.touch();
                             handle_events(&mut self, code, tx.clone(),modifiers);
                         }
                         Event::Resize(_, _) => continue,
                         _ => {}
                     }
                 }
-                Some(_) = rx.recv() => continue,
+                Some(message) = rx.recv() => match message {
+                    Message::Job(event) => {
+                        let message = self.jobs.apply(event);
+                        if let Some(message) = message.filter(|message| !message.is_empty()) {
+                            self.status = Some(message);
+                        }
+                    }
+                    // Jobs started before the session was last locked are
+                    // ignored, so they can't unlock it again.
+                    Message::Unlocked(passphrase, generation) => {
+                        if generation == self.keys.generation() {
+                            unlock_session(&mut self, passphrase, &tx);
+                        }
+                    }
+                    Message::Forget(generation) => {
+                        if generation == self.keys.generation() {
+                            self.keys.lock();
+                        }
+                    }
+                    Message::Document(document, content) => {
+                        open_editor(&mut self, document, content)
+                    }
+                    Message::Confirm(prompt, action) => {
+                        // Dropped if another prompt opened meanwhile.
+                        if matches!(self.mode, ViewMode::ListView | ViewMode::ContentView) {
+                            self.prompt = prompt;
+                            self.pending = Some(action);
+                            self.mode = ViewMode::Confirm;
+                        }
+                    }
+                    Message::Report(report) => {
+                        self.report = report;
+                        // A prompt in progress is left alone; the report
+                        // shows once it closes.
+                        if matches!(self.mode, ViewMode::ListView | ViewMode::ContentView) {
+                            self.text_scroll_y = 0;
+                            self.mode = ViewMode::Report;
+                        }
+                    }
+                    Message::Found { id, paths, done } => {
+                        // Batches of a closed finder may still be queued.
+                        if let Some(finder) = self.finder.as_mut().filter(|finder| finder.id() == id) {
+                            finder.add(paths, done);
+                        }
+                    }
+                    Message::Hits { id, hits, searched, done } => {
+                        if let Some(search) = self.search.as_mut().filter(|search| search.id() == Some(id)) {
+                            search.add(hits, searched, done);
+                        }
+                    }
+                    Message::Refresh => {}
+                },
+                _ = tokio::time::sleep(idle.unwrap_or_default()), if idle.is_some() => {
+                    if self.keys.expire() {
+                        self.files.lock().unwrap().forget_decrypted();
+                        self.status = Some(String::from("Locked after inactivity"));
+                    }
+                }
             }
         }
         Ok(())
@@ -101,9 +292,14 @@ impl Widget for &mut FileScout {
             return;
         }
 
-        let [pwd_area, files_area, message] = Layout::vertical([
+        let job_rows = match self.jobs.jobs.len().min(MAX_JOB_ROWS) {
+            0 => 0,
+            rows => rows as u16 + 2,
+        };
+        let [pwd_area, files_area, jobs_area, message] = Layout::vertical([
             Constraint::Length(1),
             Constraint::Fill(1),
+            Constraint::Length(job_rows),
             Constraint::Length(1),
         ])
         .areas(area);
@@ -128,7 +324,17 @@ impl Widget for &mut FileScout {
                 }
             }
         }
+        self.render_jobs(jobs_area, buf);
         self.render_message(message, buf, &mut file_ex);
+        if self.mode == ViewMode::Report {
+            self.render_report(files_area, buf);
+        }
+        if self.mode == ViewMode::Finder {
+            self.render_finder(files_area, buf);
+        }
+        if self.mode == ViewMode::Search {
+            self.render_search(files_area, buf);
+        }
     }
 }
 
@@ -142,7 +348,10 @@ impl FileScout {
             " <Ctrl+C> ".blue().bold().fg(sel_color),
         ]);
 
-        let block = Block::bordered().title_bottom(instruction.centered());
+        let mut block = Block::bordered().title_bottom(instruction.centered());
+        if self.document.is_some() {
+            block = block.title(" Encrypted ");
+        }
         let text = Text::from(self.input.content.to_string());
         Paragraph::new(text).block(block).render(area, buf);
     }
@@ -190,18 +399,36 @@ impl FileScout {
             .border_style(Style::new().fg(sel_color))
             .render(area, buf);
 
-        let padded_area = area.inner(Margin::new(1, 0));
+        let mut padded_area = area.inner(Margin::new(1, 0));
+        let query = match &file_struct.narrowed {
+            Some(narrowed) => Some(narrowed.query.as_str()),
+            None => (self.mode == ViewMode::Filter).then_some(""),
+        };
+        if let Some(query) = query {
+            let [list_area, query_area] =
+                Layout::vertical([Constraint::Fill(1), Constraint::Length(1)]).areas(padded_area);
+            Line::from(format!("/{query}"))
+                .fg(sel_color)
+                .render(query_area, buf);
+            padded_area = list_area;
+        }
 
         let files = file_struct.current_dir.iter().map(|name| {
-            let value = name
-                .strip_prefix(&file_struct.pwd)
-                .unwrap()
-                .to_str()
-                .unwrap();
+            let positions = file_struct
+                .narrowed
+                .as_ref()
+                .and_then(|narrowed| narrowed.positions.get(name));
+            let mut spans = highlighted(
+                &file_struct.label(name),
+                positions.map_or(&[], Vec::as_slice),
+            );
+            if file_struct.marked.contains(name) {
+                spans.insert(0, Span::from("* "));
+            }
             if name.is_dir() {
-                ListItem::new(Line::from(value).fg(sel_color))
+                ListItem::new(Line::from(spans).fg(sel_color))
             } else {
-                ListItem::new(Line::from(value).fg(un_color))
+                ListItem::new(Line::from(spans).fg(un_color))
             }
         });
         let list = List::new(files)
@@ -216,6 +443,23 @@ impl FileScout {
         match self.mode {
             ViewMode::Rename => self.render_window(padded_area, buf, " Rename "),
             ViewMode::Create => self.render_window(padded_area, buf, " New File "),
+            ViewMode::Passphrase if self.first_passphrase.is_some() => {
+                self.render_window(padded_area, buf, " Repeat Passphrase ")
+            }
+            ViewMode::Passphrase => {
+                let title = match &self.pending {
+                    Some(CryptoAction::Rekey {
+                        needs_old: true,
+                        old: None,
+                        ..
+                    }) => " Current Passphrase ",
+                    Some(CryptoAction::Rekey { .. }) => " New Passphrase ",
+                    _ => " Passphrase ",
+                };
+                self.render_window(padded_area, buf, title)
+            }
+            ViewMode::Confirm => self.render_window(padded_area, buf, " Confirm (y/n) "),
+            ViewMode::SaveAs => self.render_window(padded_area, buf, " Decrypt As "),
             _ => {}
         }
     }
@@ -234,7 +478,159 @@ impl FileScout {
             .title_alignment(Alignment::Left)
             .border_style(Style::new().fg(sel_color));
 
-        Paragraph::new(self.input.content.as_str())
+        let content = match self.mode {
+            ViewMode::Passphrase => "*".repeat(self.input.content.chars().count()),
+            ViewMode::Confirm => self.prompt.to_string(),
+            _ => self.input.content.to_string(),
+        };
+        Paragraph::new(content).block(block).render(window, buf);
+    }
+
+    fn render_finder(&mut self, area: Rect, buf: &mut Buffer) {
+        let (sel_color, un_color) = COLORS[self.color_index];
+        let Some(finder) = &mut self.finder else {
+            return;
+        };
+        let [window] = Layout::horizontal([Constraint::Percentage(80)])
+            .flex(Flex::Center)
+            .areas(area);
+        let [window] = Layout::vertical([Constraint::Percentage(80)])
+            .flex(Flex::Center)
+            .areas(window);
+
+        Clean.render(window, buf);
+        let progress = if finder.scanning {
+            format!(
+                " {}/{} found, searching ",
+                finder.result_count(),
+                finder.found()
+            )
+        } else {
+            format!(" {}/{} found ", finder.result_count(), finder.found())
+        };
+        let block = Block::bordered()
+            .title(" Find ")
+            .title_bottom(Line::from(progress).right_aligned())
+            .border_style(Style::new().fg(sel_color));
+        let inner = block.inner(window);
+        block.render(window, buf);
+
+        let [query_area, list_area] =
+            Layout::vertical([Constraint::Length(1), Constraint::Fill(1)]).areas(inner);
+        Line::from(format!("> {}", finder.query))
+            .fg(sel_color)
+            .render(query_area, buf);
+        let results = finder
+            .results()
+            .map(|(path, positions)| ListItem::new(Line::from(highlighted(path, positions))))
+            .collect::<Vec<_>>();
+        let list = List::new(results).highlight_style(Style::new().bg(sel_color).fg(un_color));
+        StatefulWidget::render(list, list_area, buf, &mut finder.state);
+    }
+
+    fn render_search(&mut self, area: Rect, buf: &mut Buffer) {
+        let (sel_color, un_color) = COLORS[self.color_index];
+        let Some(search) = &mut self.search else {
+            return;
+        };
+        let [window] = Layout::horizontal([Constraint::Percentage(80)])
+            .flex(Flex::Center)
+            .areas(area);
+        let [window] = Layout::vertical([Constraint::Percentage(80)])
+            .flex(Flex::Center)
+            .areas(window);
+
+        Clean.render(window, buf);
+        let count = search.hits().len();
+        let progress = if !search.is_current() {
+            String::from(" Search <Enter> ")
+        } else if search.scanning {
+            format!(" {count} matches in {} files, searching ", search.searched)
+        } else if search.is_truncated() {
+            format!(" First {count} matches ")
+        } else {
+            format!(" {count} matches in {} files ", search.searched)
+        };
+        let kind = if search.regex { " Regex " } else { " Text " };
+        let block = Block::bordered()
+            .title(" Search ")
+            .title(Line::from(format!("{kind}<Tab> ")).right_aligned())
+            .title_bottom(Line::from(progress).right_aligned())
+            .border_style(Style::new().fg(sel_color));
+        let inner = block.inner(window);
+        block.render(window, buf);
+
+        let [query_area, list_area, context_area] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Fill(1),
+            Constraint::Length(CONTEXT as u16 * 2 + 2),
+        ])
+        .areas(inner);
+        Line::from(format!("> {}", search.pattern))
+            .fg(sel_color)
+            .render(query_area, buf);
+
+        let items = search
+            .hits()
+            .iter()
+            .map(|hit| {
+                let path = hit.path.strip_prefix(&search.root).unwrap_or(&hit.path);
+                let mut spans =
+                    vec![Span::from(format!("{}:{}: ", path.display(), hit.line)).fg(sel_color)];
+                spans.extend(matched(&hit.text, &hit.ranges));
+                ListItem::new(Line::from(spans))
+            })
+            .collect::<Vec<_>>();
+        let list = List::new(items).highlight_style(Style::new().bg(sel_color).fg(un_color));
+        StatefulWidget::render(list, list_area, buf, &mut search.state);
+
+        let Some(hit) = search.selected() else {
+            return;
+        };
+        let first = hit.line - hit.before.len();
+        let lines = hit
+            .before
+            .iter()
+            .chain([&hit.text])
+            .chain(&hit.after)
+            .enumerate()
+            .map(|(offset, text)| {
+                let number = Span::from(format!("{:>5} ", first + offset)).fg(sel_color);
+                if first + offset == hit.line {
+                    let mut spans = vec![number];
+                    spans.extend(matched(text, &hit.ranges));
+                    Line::from(spans)
+                } else {
+                    Line::from(vec![number, Span::from(text.as_str())])
+                }
+            })
+            .collect::<Vec<_>>();
+        Paragraph::new(lines)
+            .block(
+                Block::new()
+                    .borders(Borders::TOP)
+                    .border_style(Style::new().fg(sel_color)),
+            )
+            .render(context_area, buf);
+    }
+
+    fn render_report(&self, area: Rect, buf: &mut Buffer) {
+        let (sel_color, _) = COLORS[self.color_index];
+        let [window] = Layout::horizontal([Constraint::Percentage(80)])
+            .flex(Flex::Center)
+            .areas(area);
+        let height = u16::try_from(self.report.len()).unwrap_or(u16::MAX);
+        let [window] = Layout::vertical([Constraint::Length(height.saturating_add(2))])
+            .flex(Flex::Center)
+            .areas(window);
+
+        Clean.render(window, buf);
+        let block = Block::bordered()
+            .title(" Batch Results ")
+            .title_bottom(Line::from(" Close <Esc> ").right_aligned())
+            .border_style(Style::new().fg(sel_color));
+        Paragraph::new(Text::from(self.report.join("\n")))
+            .scroll((self.text_scroll_y as u16, 0))
             .block(block)
             .render(window, buf);
     }
@@ -268,7 +664,13 @@ impl FileScout {
 
     fn render_content(&mut self, area: Rect, buf: &mut Buffer, file_struct: &mut FileStruct) {
         let (sel_col, un_col) = COLORS[self.color_index];
-        if !file_struct.content.is_empty() {
+        if file_struct.encrypted.is_some() && file_struct.content.is_empty() {
+            Widget::render(
+                Text::from("Encrypted, press P to preview").fg(sel_col),
+                area,
+                buf,
+            );
+        } else if !file_struct.content.is_empty() {
             let text = Text::from(file_struct.content.to_string());
             Paragraph::new(text.fg(un_col))
                 .scroll((self.text_scroll_y as u16, self.text_scroll_x as u16))
@@ -278,6 +680,34 @@ impl FileScout {
         }
     }
 
+    fn render_jobs(&self, area: Rect, buf: &mut Buffer) {
+        if self.jobs.jobs.is_empty() {
+            return;
+        }
+        let (sel_color, _) = COLORS[self.color_index];
+        let block = Block::bordered()
+            .title(" Jobs ")
+            .title_bottom(Line::from(" Cancel <X> ").right_aligned())
+            .border_style(Style::new().fg(sel_color));
+        let inner = block.inner(area);
+        block.render(area, buf);
+
+        let rows = Layout::vertical([Constraint::Length(1); MAX_JOB_ROWS]).split(inner);
+        for (job, row) in self.jobs.jobs.iter().rev().zip(rows.iter()) {
+            let mut label = format!("{} {:>3}%", job.label, (job.ratio() * 100.0) as u8);
+            if job.is_cancelled() {
+                label.push_str(" cancelling");
+            } else if let Some(current) = &job.current {
+                label = format!("{label} {}", current.display());
+            }
+            LineGauge::default()
+                .filled_style(Style::new().fg(sel_color))
+                .ratio(job.ratio())
+                .label(label)
+                .render(*row, buf);
+        }
+    }
+
     fn render_message(&mut self, area: Rect, buf: &mut Buffer, file_struct: &mut FileStruct) {
         #[cfg(unix)]
         let (sel_color, _) = COLORS[self.color_index];
@@ -291,10 +721,59 @@ impl FileScout {
         .left_aligned()
         .render(area, buf);
 
+        let lock = if self.keys.is_unlocked() {
+            "Unlocked"
+        } else {
+            "Locked"
+        };
+        let listed = if file_struct.filter.is_active() {
+            format!("{} hidden", file_struct.hidden)
+        } else {
+            String::from("all files")
+        };
+        Paragraph::new(Text::from(format!("{lock} · {listed}")).bold())
+            .centered()
+            .render(area, buf);
+
         if let Some(error) = &file_struct.error {
             Paragraph::new(Text::from(error.to_string()).left_aligned().bold())
                 .right_aligned()
                 .render(area, buf);
+        } else if let Some(status) = &self.status {
+            Paragraph::new(Text::from(status.as_str()).left_aligned())
+                .right_aligned()
+                .render(area, buf);
         }
     }
 }
+
+/// `text` with the characters at `positions` underlined, for fuzzy matches.
+fn highlighted<'a>(text: &str, positions: &[usize]) -> Vec<Span<'a>> {
+    text.chars()
+        .enumerate()
+        .map(|(index, ch)| {
+            if positions.contains(&index) {
+                Span::from(ch.to_string()).bold().underlined()
+            } else {
+                Span::from(ch.to_string())
+            }
+        })
+        .collect()
+}
+
+/// `text` with the byte `ranges` that a search matched underlined.
+fn matched<'a>(text: &str, ranges: &[std::ops::Range<usize>]) -> Vec<Span<'a>> {
+    let mut spans = Vec::new();
+    let mut end = 0;
+    for range in ranges {
+        spans.push(Span::from(text[end..range.start].to_string()));
+        spans.push(
+            Span::from(text[range.clone()].to_string())
+                .bold()
+                .underlined(),
+        );
+        end = range.end;
+    }
+    spans.push(Span::from(text[end..].to_string()));
+    spans
+}
@@ -0,0 +1,75 @@
+//! Walks below a directory on a thread of its own, posting what it finds in
+//! batches so results show up while the walk is still going. The finder and
+//! the content search each run on one.
+
+use std::{
+    mem,
+    ops::ControlFlow,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use ignore::{DirEntry, WalkBuilder};
+use tokio::sync::mpsc::Sender;
+
+use crate::ui::Message;
+
+/// How often a walk posts what it found since the last batch.
+const BATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A walk in progress, stopped once dropped.
+pub struct Walk {
+    /// Tells the walk's batches apart from those of earlier ones.
+    pub id: usize,
+    cancel: Arc<AtomicBool>,
+}
+
+impl Walk {
+    /// Hands every entry `walker` comes across to `visit`, along with the
+    /// batch being gathered and the flag that is set once the walk is
+    /// stopped. Batches are posted as the messages `post` makes of them, the
+    /// last one once `visit` breaks or the walk is over.
+    pub fn start<B: Default + Send + 'static>(
+        walker: WalkBuilder,
+        tx: Sender<Message>,
+        mut visit: impl FnMut(DirEntry, &mut B, &AtomicBool) -> ControlFlow<()> + Send + 'static,
+        post: impl Fn(usize, B, bool) -> Message + Send + 'static,
+    ) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let cancel = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&cancel);
+        thread::spawn(move || {
+            let mut batch = B::default();
+            let mut last_batch = Instant::now();
+            for entry in walker.build().filter_map(|entry| entry.ok()) {
+                if stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                if visit(entry, &mut batch, &stop).is_break() {
+                    break;
+                }
+                if last_batch.elapsed() >= BATCH_INTERVAL {
+                    last_batch = Instant::now();
+                    let message = post(id, mem::take(&mut batch), false);
+                    if tx.blocking_send(message).is_err() {
+                        return;
+                    }
+                }
+            }
+            let _ = tx.blocking_send(post(id, batch, true));
+        });
+        Self { id, cancel }
+    }
+}
+
+impl Drop for Walk {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}